@@ -1,7 +1,9 @@
 use std::collections::HashMap;
+use std::path::{Path, PathBuf};
 
 use bevy::prelude::*;
-use bevy::asset::LoadedFolder;
+use bevy::asset::{LoadState, LoadedFolder, UntypedAssetLoadFailedEvent};
+use bevy::asset::io::file::FileAssetReader;
 
 use bevy_common_assets::ron::RonAssetPlugin;
 
@@ -9,6 +11,11 @@ use crate::state::AppState;
 use crate::item::*;
 use crate::recipe::*;
 use crate::machine::*;
+use crate::ids::*;
+use crate::pack::*;
+use crate::definitions::*;
+use crate::validation::*;
+use crate::scenario::*;
 
 pub struct AssetPlugin;
 
@@ -18,108 +25,442 @@ impl Plugin for AssetPlugin {
             RonAssetPlugin::<ItemType>::new(&["item.ron"]),
             RonAssetPlugin::<RecipeTemplate>::new(&["recipe.ron"]),
             RonAssetPlugin::<MachineTemplate>::new(&["machine.ron"]),
+            RonAssetPlugin::<ItemPatch>::new(&["item.patch.ron"]),
+            RonAssetPlugin::<RecipePatch>::new(&["recipe.patch.ron"]),
+            RonAssetPlugin::<MachinePatch>::new(&["machine.patch.ron"]),
+            RonAssetPlugin::<Scenario>::new(&["scenario.ron"]),
         ));
         app.add_systems(
             OnEnter(AppState::LoadingAssetFolders),
             load_asset_folders);
+        app.init_resource::<LoadingProgress>()
+            .init_resource::<ValidationReport>();
         app.add_systems(
             Update,
-            check_asset_folders.run_if(in_state(AppState::LoadingAssetFolders)),
+            (track_loading_progress, check_asset_folders, fail_on_load_error)
+                .chain()
+                .run_if(in_state(AppState::LoadingAssetFolders)),
         );
         app.add_systems(
             OnEnter(AppState::LoadingAssets),
-            (load_item_types, load_recipes, load_machines).chain()
+            (collect_definitions, load_item_types, load_recipes, load_machines).chain()
         );
         app.add_systems(
             Update,
-            start_game.run_if(
+            start_validation.run_if(
                     in_state(AppState::LoadingAssets)
                     .and_then(resource_exists::<ItemTypeList>)
                     .and_then(resource_exists::<RecipeList>)
                     .and_then(resource_exists::<MachineList>)
             )
         );
+        app.add_systems(
+            OnEnter(AppState::ValidatingAssets),
+            validate_assets
+        );
+        app.add_event::<DefinitionsReloaded>();
+        app.add_systems(
+            Update,
+            reload_definitions.run_if(in_state(AppState::InGame))
+        );
+    }
+
+    // bevy's asset plugin may be added after this one
+    fn finish(&self, app: &mut App) {
+        let asset_root = AssetRoot::of_app(app);
+        app.insert_resource(asset_root);
+    }
+}
+
+// the directory the asset server reads from, for anything that has to look at the files directly
+#[derive(Resource, Clone, Debug)]
+pub struct AssetRoot(pub PathBuf);
+
+impl AssetRoot {
+    // follows the `file_path` bevy's asset plugin was set up with
+    pub fn of_app(app: &App) -> Self {
+        let file_path = app
+            .get_added_plugins::<bevy::asset::AssetPlugin>()
+            .first()
+            .map_or_else(|| bevy::asset::AssetPlugin::default().file_path, |p| p.file_path.clone());
+        AssetRoot(FileAssetReader::get_base_path().join(file_path))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
     }
 }
 
+// sent after changed definition files were loaded into the lists
+#[derive(Event)]
+pub struct DefinitionsReloaded;
+
 #[allow(dead_code)]
 #[derive(Resource)]
 struct AssetFolders {
-    item_type_folder_handle: Handle<LoadedFolder>,
-    recipe_folder_handle: Handle<LoadedFolder>,
-    machine_folder_handle: Handle<LoadedFolder>,
+    // content folders of every pack, in load order
+    folder_handles: Vec<Handle<LoadedFolder>>,
 }
 #[warn(dead_code)]
 
 fn load_asset_folders(
     mut commands: Commands,
     server: Res<AssetServer>,
+    scenario: Option<Res<SelectedScenario>>,
+    asset_root: Res<AssetRoot>,
+) {
+    let packs = ContentPacks::discover(asset_root.path());
+    let folder_handles = packs
+        .iter()
+        .flat_map(|pack| pack.content_folders(asset_root.path()))
+        .map(|folder| server.load_folder(folder))
+        .collect();
+    commands.insert_resource(AssetFolders { folder_handles });
+    commands.insert_resource(packs);
+    if let Some(scenario) = scenario {
+        commands.insert_resource(ScenarioHandle(server.load(scenario.0.clone())));
+    }
+}
+
+// how far loading the asset folders got, for a loading screen
+// a folder counts as one asset until it has been read, then every file in it counts
+#[derive(Resource, Default, Clone, PartialEq, Eq, Debug)]
+pub struct LoadingProgress {
+    pub total: usize,
+    pub loaded: usize,
+    pub failed: usize,
+}
+
+impl LoadingProgress {
+    fn count(&mut self, state: LoadState) {
+        self.total += 1;
+        match state {
+            LoadState::Loaded => self.loaded += 1,
+            LoadState::Failed(_) => self.failed += 1,
+            LoadState::NotLoaded | LoadState::Loading => (),
+        }
+    }
+
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        self.loaded as f32 / self.total as f32
+    }
+}
+
+fn track_loading_progress(
+    server: Res<AssetServer>,
+    folders: Res<AssetFolders>,
+    scenario: Option<Res<ScenarioHandle>>,
+    loaded_folders: Res<Assets<LoadedFolder>>,
+    mut progress: ResMut<LoadingProgress>,
 ) {
-    commands.insert_resource(
-        AssetFolders {
-            item_type_folder_handle: server.load_folder("items"),
-            recipe_folder_handle: server.load_folder("recipes"),
-            machine_folder_handle: server.load_folder("machines"),
+    let mut new_progress = LoadingProgress::default();
+    if let Some(scenario) = scenario {
+        new_progress.count(server.load_state(scenario.0.id()));
+    }
+    for handle in folders.folder_handles.iter() {
+        match loaded_folders.get(handle) {
+            Some(folder) => {
+                for file in folder.handles.iter() {
+                    new_progress.count(server.load_state(file.id()));
+                }
+            }
+            None => new_progress.count(server.load_state(handle.id())),
         }
-    );
+    }
+    // only touch the resource when something changed, so a loading screen can use change detection
+    if *progress != new_progress {
+        *progress = new_progress;
+    }
 }
 
+// move on once every folder has loaded together with all of its files, and the scenario if there is one
 fn check_asset_folders(
     mut app_next_state: ResMut<NextState<AppState>>,
-    mut events: EventReader<AssetEvent<LoadedFolder>>,
+    server: Res<AssetServer>,
+    folders: Res<AssetFolders>,
+    scenario: Option<Res<ScenarioHandle>>,
+) {
+    let scenario_loaded = scenario.is_none_or(|s| server.is_loaded_with_dependencies(&s.0));
+    if scenario_loaded && folders.folder_handles.iter().all(|h| server.is_loaded_with_dependencies(h)) {
+        println!("Asset folders loaded!");
+        app_next_state.set(AppState::LoadingAssets);
+    }
+}
+
+// a file or folder that can't be loaded stops the game from starting, it is reported like validation problems
+fn fail_on_load_error(
+    mut app_next_state: ResMut<NextState<AppState>>,
+    mut events: EventReader<UntypedAssetLoadFailedEvent>,
+    mut report: ResMut<ValidationReport>,
 ) {
     for event in events.read() {
-        if let AssetEvent::LoadedWithDependencies { id: _id } = event {
-            println!("Asset folder loaded!");
-            app_next_state.set(AppState::LoadingAssets);
-        }
+        error!("Failed to load {}: {}", event.path, event.error);
+        report.push(&event.path.to_string(), event.error.to_string());
+        app_next_state.set(AppState::AssetError);
     }
 }
 
-fn load_item_types(
+fn collect_definitions(
     mut commands: Commands,
-    item_types: Res<Assets<ItemType>>,
+    assets: DefinitionAssets,
+    packs: Res<ContentPacks>,
 ) {
-    let mut type_list = ItemTypeList(HashMap::<u16, ItemType>::new());
-    for (id, item_type) in item_types.iter() {
-        println!("{}, name: {}, id: {} max stack: {}", id, item_type.name, item_type.id, item_type.max_stack);
-        type_list.0.insert(item_type.id, item_type.clone());
-    } 
+    let definitions = Definitions::collect(&assets, &packs);
+    definitions.print_report(&packs);
+    commands.insert_resource(definitions);
+}
 
-    commands.insert_resource(type_list)
+fn load_item_types(
+    mut commands: Commands,
+    definitions: Res<Definitions>,
+    mut report: ResMut<ValidationReport>,
+) {
+    let mut registry = IdRegistry::default();
+    let type_list = build_item_types(&definitions, &mut registry, &mut report);
+    commands.insert_resource(type_list);
+    commands.insert_resource(registry);
 }
 
 fn load_recipes(
     mut commands: Commands,
-    recipe_templates: Res<Assets<RecipeTemplate>>,
+    definitions: Res<Definitions>,
     item_types: Res<ItemTypeList>,
+    mut registry: ResMut<IdRegistry>,
+    mut report: ResMut<ValidationReport>,
 ) {
-    let mut recipe_list = RecipeList(HashMap::<u16, Recipe>::new());
-    for (id, template) in recipe_templates.iter() {
-        println!("{}, name: {}, id: {}", id, template.name, template.id);
-        let new_recipe = Recipe::from_template(template, &item_types);
-        recipe_list.0.insert(template.id, new_recipe);
-    } 
-
-    commands.insert_resource(recipe_list)
+    commands.insert_resource(build_recipes(&definitions, &item_types, &mut registry, &mut report))
 }
 
 fn load_machines(
     mut commands: Commands,
-    machines: Res<Assets<MachineTemplate>>,
+    definitions: Res<Definitions>,
+    mut registry: ResMut<IdRegistry>,
+    mut report: ResMut<ValidationReport>,
 ) {
-    let mut machine_list = MachineList(HashMap::<u16, MachineTemplate>::new());
-    for (id, machine) in machines.iter() {
-        println!("{}, name: {}, id: {}, crafting speed: {}", id, machine.name, machine.id, machine.crafting_speed);
-        machine_list.0.insert(machine.id, machine.clone());
+    commands.insert_resource(build_machines(&definitions, &mut registry, &mut report))
+}
+
+// the list builders intern every id they see, ids already in the registry are kept
+// definitions are sorted by key, so runtime ids don't depend on asset load order
+// a definition that can't get an id is left out and reported
+pub fn build_item_types(definitions: &Definitions, registry: &mut IdRegistry, report: &mut ValidationReport) -> ItemTypeList {
+    let mut type_list = ItemTypeList::default();
+    for definition in definitions.items.iter() {
+        let mut item_type = definition.value.clone();
+        let Some(id) = registry.items.intern(&item_type.key) else {
+            report.push(&definition.file, format!("item `{}` can't get an id, there are too many items", item_type.key));
+            continue;
+        };
+        item_type.id = id;
+        println!("{}, name: {}, id: {} ({}) max stack: {}, category: {}, tags: {:?}",
+            definition.file, item_type.name, item_type.key, item_type.id, item_type.max_stack, item_type.category, item_type.tags);
+        type_list.insert(item_type);
+    } 
+    type_list
+}
+
+pub fn build_recipes(
+    definitions: &Definitions,
+    item_types: &ItemTypeList,
+    registry: &mut IdRegistry,
+    report: &mut ValidationReport,
+) -> RecipeList {
+    let mut recipe_list = RecipeList(HashMap::<u16, Recipe>::new());
+    for definition in definitions.recipes.iter() {
+        let mut template = definition.value.clone();
+        let Some(id) = registry.recipes.intern(&template.key) else {
+            report.push(&definition.file, format!("recipe `{}` can't get an id, there are too many recipes", template.key));
+            continue;
+        };
+        template.id = id;
+        println!("{}, name: {}, id: {} ({})", definition.file, template.name, template.key, template.id);
+        // broken recipes are reported by the validation stage
+        match Recipe::from_template(&template, item_types, registry) {
+            Ok(new_recipe) => { recipe_list.0.insert(template.id, new_recipe); }
+            Err(e) => warn!("Skipping recipe: {}", e),
+        }
     } 
+    recipe_list
+}
 
-    commands.insert_resource(machine_list)
+pub fn build_machines(definitions: &Definitions, registry: &mut IdRegistry, report: &mut ValidationReport) -> MachineList {
+    let mut machine_list = MachineList(HashMap::<u16, MachineTemplate>::new());
+    for definition in definitions.machines.iter() {
+        let mut machine = definition.value.clone();
+        let Some(id) = registry.machines.intern(&machine.key) else {
+            report.push(&definition.file, format!("machine `{}` can't get an id, there are too many machines", machine.key));
+            continue;
+        };
+        machine.id = id;
+        // listed recipes first, in their order, then the ones from categories by key
+        let mut valid_recipe_ids: Vec<u16> = machine.valid_recipes.iter().filter_map(|k| registry.recipes.get(k)).collect();
+        for recipe in definitions.recipes.iter().map(|r| &r.value) {
+            let id = registry.recipes.get(&recipe.key);
+            if let Some(id) = id.filter(|id| !valid_recipe_ids.contains(id) && machine.accepts_recipe(recipe)) {
+                valid_recipe_ids.push(id);
+            }
+        }
+        machine.valid_recipe_ids = valid_recipe_ids;
+        println!("{}, name: {}, id: {} ({}), crafting speed: {}, categories: {:?}, recipes: {:?}",
+            definition.file, machine.name, machine.key, machine.id, machine.crafting_speed, machine.crafting_categories, machine.valid_recipe_ids);
+        machine_list.0.insert(machine.id, machine);
+    } 
+    machine_list
 }
 
-fn start_game(
+fn start_validation(
     mut app_next_state: ResMut<NextState<AppState>>,
 ) {
-    println!("Starting game!");
-    app_next_state.set(AppState::InGame);
+    println!("Validating assets!");
+    app_next_state.set(AppState::ValidatingAssets);
+}
+
+// rebuild the definition lists when their files change during the game
+// problems are reported and the old definitions kept, so a half edited file can't break a running game
+#[allow(clippy::too_many_arguments)]
+pub fn reload_definitions(
+    mut commands: Commands,
+    assets: DefinitionAssets,
+    mut events: DefinitionEvents,
+    packs: Res<ContentPacks>,
+    asset_root: Res<AssetRoot>,
+    mut definitions: ResMut<Definitions>,
+    mut item_types: ResMut<ItemTypeList>,
+    mut recipes: ResMut<RecipeList>,
+    mut machines: ResMut<MachineList>,
+    mut registry: ResMut<IdRegistry>,
+    mut reloaded: EventWriter<DefinitionsReloaded>,
+) {
+    if !events.modified() {
+        return;
+    }
+    println!("Definition files changed, reloading!");
+    // start from the current ids so live components keep pointing at the same definitions
+    let mut new_registry = registry.clone();
+    let new_definitions = Definitions::collect(&assets, &packs);
+    let mut report = ValidationReport::default();
+    let new_item_types = build_item_types(&new_definitions, &mut new_registry, &mut report);
+    let new_recipes = build_recipes(&new_definitions, &new_item_types, &mut new_registry, &mut report);
+    let new_machines = build_machines(&new_definitions, &mut new_registry, &mut report);
+    report.append(&mut validate_definitions(&new_definitions, &new_item_types, asset_root.path()));
+    report.print_warnings();
+
+    if report.is_empty() {
+        new_definitions.print_report(&packs);
+        *definitions = new_definitions;
+        *item_types = new_item_types;
+        *recipes = new_recipes;
+        *machines = new_machines;
+        *registry = new_registry;
+        reloaded.send(DefinitionsReloaded);
+        println!("Definitions reloaded!");
+    } else {
+        error!("Not reloading, found {} problem(s) in the asset files:", report.problems.len());
+        for problem in report.problems.iter() {
+            error!("  {}", problem);
+        }
+    }
+    commands.insert_resource(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use bevy::asset::AssetLoadError;
+    use bevy::state::app::StatesPlugin;
+
+    use super::*;
+    use crate::simulation::*;
+    use crate::testing::*;
+
+    #[test]
+    fn definitions_without_an_id_are_reported() {
+        let mut registry = IdRegistry::default();
+        for i in 0..=u16::MAX {
+            registry.items.intern(&format!("test:filler_{}", i));
+        }
+        let mut definitions = Definitions::default();
+        definitions.items.push(Definition {
+            value: item(0, 10),
+            file: "items/extra.item.ron".to_string(),
+            pack: 0,
+            overridden: Vec::new(),
+            patches: Vec::new(),
+        });
+        let mut report = ValidationReport::default();
+        let item_types = build_item_types(&definitions, &mut registry, &mut report);
+        assert_eq!(item_types.iter().count(), 0);
+        assert_eq!(report.problems.len(), 1);
+        assert_eq!(report.problems[0].file, "items/extra.item.ron");
+    }
+
+    #[test]
+    fn progress_counts_only_loaded_files() {
+        let mut progress = LoadingProgress::default();
+        assert_eq!(progress.fraction(), 1.0);
+        progress.count(LoadState::Loaded);
+        progress.count(LoadState::Loading);
+        progress.count(LoadState::NotLoaded);
+        assert_eq!(progress.fraction(), 1.0 / 3.0);
+        progress.count(LoadState::Failed(Box::new(AssetLoadError::AssetMetaReadError)));
+        assert_eq!((progress.total, progress.loaded, progress.failed), (4, 1, 1));
+        assert_eq!(progress.fraction(), 0.25);
+    }
+
+    fn loading_app(asset_root: &Path) -> App {
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            bevy::asset::AssetPlugin { file_path: asset_root.to_string_lossy().into_owned(), ..default() },
+            StatesPlugin,
+            SimulationPlugin,
+        ));
+        app.finish();
+        app.cleanup();
+        app
+    }
+
+    // update until loading ends in the game or an error, return every state it went through
+    fn load(app: &mut App) -> Vec<AppState> {
+        let started = Instant::now();
+        let mut states = vec![*app.world().resource::<State<AppState>>().get()];
+        while !matches!(states.last(), Some(AppState::InGame | AppState::AssetError)) {
+            assert!(started.elapsed() < Duration::from_secs(30), "loading didn't finish, stuck in {:?}", states.last());
+            app.update();
+            let state = *app.world().resource::<State<AppState>>().get();
+            if states.last() != Some(&state) {
+                states.push(state);
+            }
+        }
+        states
+    }
+
+    #[test]
+    fn the_repository_assets_load_into_the_game() {
+        let mut app = loading_app(Path::new(concat!(env!("CARGO_MANIFEST_DIR"), "/assets")));
+        assert_eq!(
+            load(&mut app),
+            vec![AppState::LoadingAssetFolders, AppState::LoadingAssets, AppState::ValidatingAssets, AppState::InGame]
+        );
+        let progress = app.world().resource::<LoadingProgress>();
+        assert!(progress.total > 0);
+        assert_eq!((progress.fraction(), progress.failed), (1.0, 0));
+        assert!(app.world().resource::<ValidationReport>().is_empty());
+    }
+
+    #[test]
+    fn a_broken_file_stops_loading() {
+        let asset_root = std::env::temp_dir().join(format!("bevy-automation-broken-assets-{}", std::process::id()));
+        std::fs::create_dir_all(asset_root.join("items")).unwrap();
+        std::fs::write(asset_root.join("items/broken.item.ron"), "(name: ").unwrap();
+        let mut app = loading_app(&asset_root);
+        let states = load(&mut app);
+        std::fs::remove_dir_all(&asset_root).unwrap();
+        assert_eq!(states, vec![AppState::LoadingAssetFolders, AppState::AssetError]);
+        assert!(!app.world().resource::<ValidationReport>().is_empty());
+    }
 }
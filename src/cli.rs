@@ -0,0 +1,44 @@
+use std::path::PathBuf;
+
+use crate::scenario::DEFAULT_SCENARIO;
+
+// command line options
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    // run the simulation without a window and exit after `ticks`
+    pub headless: bool,
+    pub ticks: u64,
+    // save to load once the game has started
+    pub load: Option<PathBuf>,
+    // where a headless run saves the factory when it is done
+    pub save: Option<PathBuf>,
+    // asset path of the scenario to start with
+    pub scenario: String,
+}
+
+const DEFAULT_TICKS: u64 = 600;
+
+impl Args {
+    pub fn parse() -> Result<Args, String> {
+        Self::parse_from(std::env::args().skip(1))
+    }
+
+    pub fn parse_from(args: impl IntoIterator<Item = String>) -> Result<Args, String> {
+        let mut parsed = Args { ticks: DEFAULT_TICKS, scenario: DEFAULT_SCENARIO.to_string(), ..Default::default() };
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => parsed.headless = true,
+                "--ticks" => {
+                    let value = args.next().ok_or("--ticks needs a number of ticks")?;
+                    parsed.ticks = value.parse().map_err(|_| format!("invalid number of ticks `{}`", value))?;
+                }
+                "--load" => parsed.load = Some(args.next().ok_or("--load needs a save file")?.into()),
+                "--save" => parsed.save = Some(args.next().ok_or("--save needs a save file")?.into()),
+                "--scenario" => parsed.scenario = args.next().ok_or("--scenario needs a scenario file")?,
+                _ => return Err(format!("unknown argument `{}`", arg)),
+            }
+        }
+        Ok(parsed)
+    }
+}
@@ -0,0 +1,417 @@
+use std::collections::{BTreeMap, HashMap};
+
+use bevy::prelude::*;
+use bevy::ecs::system::SystemParam;
+
+use crate::ids::*;
+use crate::item::*;
+use crate::recipe::*;
+use crate::machine::*;
+use crate::pack::*;
+use crate::validation::*;
+
+// the definition assets as loaded from the files of every pack
+#[derive(SystemParam)]
+pub struct DefinitionAssets<'w> {
+    pub server: Res<'w, AssetServer>,
+    pub item_types: Res<'w, Assets<ItemType>>,
+    pub item_patches: Res<'w, Assets<ItemPatch>>,
+    pub recipes: Res<'w, Assets<RecipeTemplate>>,
+    pub recipe_patches: Res<'w, Assets<RecipePatch>>,
+    pub machines: Res<'w, Assets<MachineTemplate>>,
+    pub machine_patches: Res<'w, Assets<MachinePatch>>,
+}
+
+// asset events of every definition file type
+#[derive(SystemParam)]
+pub struct DefinitionEvents<'w, 's> {
+    item_types: EventReader<'w, 's, AssetEvent<ItemType>>,
+    item_patches: EventReader<'w, 's, AssetEvent<ItemPatch>>,
+    recipes: EventReader<'w, 's, AssetEvent<RecipeTemplate>>,
+    recipe_patches: EventReader<'w, 's, AssetEvent<RecipePatch>>,
+    machines: EventReader<'w, 's, AssetEvent<MachineTemplate>>,
+    machine_patches: EventReader<'w, 's, AssetEvent<MachinePatch>>,
+}
+
+impl DefinitionEvents<'_, '_> {
+    // true if any definition file changed, reads all pending events
+    pub fn modified(&mut self) -> bool {
+        let modified = [
+            was_modified(&mut self.item_types),
+            was_modified(&mut self.item_patches),
+            was_modified(&mut self.recipes),
+            was_modified(&mut self.recipe_patches),
+            was_modified(&mut self.machines),
+            was_modified(&mut self.machine_patches),
+        ];
+        modified.contains(&true)
+    }
+}
+
+// counts instead of stopping at the first match, so every event is read
+fn was_modified<A: Asset>(events: &mut EventReader<AssetEvent<A>>) -> bool {
+    events.read().filter(|e| matches!(e, AssetEvent::Modified { .. })).count() > 0
+}
+
+// definitions that packs can add, override and patch
+pub trait PackDefinition: Asset + Clone {
+    const KIND: &'static str;
+    fn key(&self) -> &str;
+    // namespace the definition's id and the ids it refers to with the pack that defines it
+    fn qualify(&mut self, pack: &ContentPack);
+}
+
+pub trait DefinitionPatch: Asset + Clone {
+    type Target: PackDefinition;
+    fn key(&self) -> &str;
+    fn qualify(&mut self, pack: &ContentPack);
+    fn apply(&self, target: &mut Self::Target);
+}
+
+fn qualify_keys(amounts: &HashMap<String, ItemCount>, namespace: &str) -> HashMap<String, ItemCount> {
+    amounts.iter().map(|(key, amount)| (qualify(key, namespace), *amount)).collect()
+}
+
+impl PackDefinition for ItemType {
+    const KIND: &'static str = "item";
+
+    fn key(&self) -> &str {
+        &self.key
+    }
+
+    fn qualify(&mut self, pack: &ContentPack) {
+        self.key = qualify(&self.key, pack.namespace());
+    }
+}
+
+impl DefinitionPatch for ItemPatch {
+    type Target = ItemType;
+
+    fn key(&self) -> &str {
+        &self.key
+    }
+
+    fn qualify(&mut self, pack: &ContentPack) {
+        self.key = qualify(&self.key, pack.namespace());
+    }
+
+    fn apply(&self, target: &mut ItemType) {
+        ItemPatch::apply(self, target)
+    }
+}
+
+impl PackDefinition for RecipeTemplate {
+    const KIND: &'static str = "recipe";
+
+    fn key(&self) -> &str {
+        &self.key
+    }
+
+    fn qualify(&mut self, pack: &ContentPack) {
+        self.key = qualify(&self.key, pack.namespace());
+        self.inputs = qualify_keys(&self.inputs, pack.namespace());
+        self.outputs = qualify_keys(&self.outputs, pack.namespace());
+    }
+}
+
+impl DefinitionPatch for RecipePatch {
+    type Target = RecipeTemplate;
+
+    fn key(&self) -> &str {
+        &self.key
+    }
+
+    fn qualify(&mut self, pack: &ContentPack) {
+        self.key = qualify(&self.key, pack.namespace());
+        self.inputs = qualify_keys(&self.inputs, pack.namespace());
+        self.outputs = qualify_keys(&self.outputs, pack.namespace());
+    }
+
+    fn apply(&self, target: &mut RecipeTemplate) {
+        RecipePatch::apply(self, target)
+    }
+}
+
+impl PackDefinition for MachineTemplate {
+    const KIND: &'static str = "machine";
+
+    fn key(&self) -> &str {
+        &self.key
+    }
+
+    // sprites are looked up in the pack's own directory
+    fn qualify(&mut self, pack: &ContentPack) {
+        self.key = qualify(&self.key, pack.namespace());
+        self.sprite_name = pack.path(&self.sprite_name);
+        self.valid_recipes = self.valid_recipes.iter().map(|k| qualify(k, pack.namespace())).collect();
+    }
+}
+
+impl DefinitionPatch for MachinePatch {
+    type Target = MachineTemplate;
+
+    fn key(&self) -> &str {
+        &self.key
+    }
+
+    fn qualify(&mut self, pack: &ContentPack) {
+        self.key = qualify(&self.key, pack.namespace());
+        self.sprite_name = self.sprite_name.as_ref().map(|s| pack.path(s));
+        self.add_recipes = self.add_recipes.iter().map(|k| qualify(k, pack.namespace())).collect();
+        self.remove_recipes = self.remove_recipes.iter().map(|k| qualify(k, pack.namespace())).collect();
+    }
+
+    fn apply(&self, target: &mut MachineTemplate) {
+        MachinePatch::apply(self, target)
+    }
+}
+
+// a definition after overrides and patches, with the files it came from
+#[derive(Clone, Debug)]
+pub struct Definition<T> {
+    pub value: T,
+    pub file: String,
+    pub pack: usize,
+    // files of earlier packs whose definition of the same id was replaced
+    pub overridden: Vec<String>,
+    // files of the patches applied on top, in order
+    pub patches: Vec<String>,
+}
+
+// the definitions of every pack merged together, ids qualified and sorted by key
+#[derive(Resource, Default, Clone)]
+pub struct Definitions {
+    pub items: Vec<Definition<ItemType>>,
+    pub recipes: Vec<Definition<RecipeTemplate>>,
+    pub machines: Vec<Definition<MachineTemplate>>,
+    // ids defined twice by one pack and patches of unknown ids
+    pub problems: ValidationReport,
+}
+
+impl Definitions {
+    pub fn collect(assets: &DefinitionAssets, packs: &ContentPacks) -> Self {
+        let mut problems = ValidationReport::default();
+        let items = merge(&assets.server, packs, &assets.item_types, &assets.item_patches, &mut problems);
+        let recipes = merge(&assets.server, packs, &assets.recipes, &assets.recipe_patches, &mut problems);
+        let machines = merge(&assets.server, packs, &assets.machines, &assets.machine_patches, &mut problems);
+        Definitions { items, recipes, machines, problems }
+    }
+
+    // list the packs and every definition that a pack changed
+    pub fn print_report(&self, packs: &ContentPacks) {
+        println!("Content packs in load order:");
+        for pack in packs.iter() {
+            let root = if pack.root.is_empty() { "assets" } else { &pack.root };
+            println!("  {} {} ({})", pack.manifest.name, pack.manifest.version, root);
+        }
+        print_merged("Items", &self.items, packs);
+        print_merged("Recipes", &self.recipes, packs);
+        print_merged("Machines", &self.machines, packs);
+    }
+}
+
+fn print_merged<D: PackDefinition>(title: &str, definitions: &[Definition<D>], packs: &ContentPacks) {
+    println!("{}: {}", title, definitions.len());
+    for definition in definitions.iter() {
+        if definition.overridden.is_empty() && definition.patches.is_empty() {
+            continue;
+        }
+        println!("  {} from {}", definition.value.key(), packs.get(definition.pack).namespace());
+        for file in definition.overridden.iter() {
+            println!("    overrides {}", file);
+        }
+        for file in definition.patches.iter() {
+            println!("    patched by {}", file);
+        }
+    }
+}
+
+// qualified copies of an asset type, with their file and pack, sorted by pack and then file
+fn sources<A: Asset + Clone>(
+    server: &AssetServer,
+    packs: &ContentPacks,
+    assets: &Assets<A>,
+    qualify: impl Fn(&mut A, &ContentPack),
+) -> Vec<(usize, String, A)> {
+    let mut sources: Vec<(usize, String, A)> = assets
+        .iter()
+        .map(|(id, asset)| {
+            let file = asset_file(server, id);
+            let pack = packs.pack_of(&file);
+            let mut asset = asset.clone();
+            qualify(&mut asset, packs.get(pack));
+            (pack, file, asset)
+        })
+        .collect();
+    sources.sort_by(|a, b| (a.0, &a.1).cmp(&(b.0, &b.1)));
+    sources
+}
+
+// merge the loaded definitions and patches of one type
+fn merge<D: PackDefinition, P: DefinitionPatch<Target = D>>(
+    server: &AssetServer,
+    packs: &ContentPacks,
+    definitions: &Assets<D>,
+    patches: &Assets<P>,
+    problems: &mut ValidationReport,
+) -> Vec<Definition<D>> {
+    let definitions = sources(server, packs, definitions, D::qualify);
+    let patches = sources(server, packs, patches, P::qualify);
+    merge_sources(packs.0.len(), &definitions, &patches, problems)
+}
+
+// go through the packs in load order, each adding or replacing definitions and then patching them
+fn merge_sources<D: PackDefinition, P: DefinitionPatch<Target = D>>(
+    pack_count: usize,
+    definitions: &[(usize, String, D)],
+    patches: &[(usize, String, P)],
+    problems: &mut ValidationReport,
+) -> Vec<Definition<D>> {
+    let mut merged = BTreeMap::<String, Definition<D>>::new();
+    for pack in 0..pack_count {
+        for (_, file, value) in definitions.iter().filter(|d| d.0 == pack) {
+            let key = value.key().to_string();
+            match merged.get_mut(&key) {
+                Some(existing) if existing.pack == pack => {
+                    problems.push(file, format!("duplicate {} id `{}`, also defined in {}", D::KIND, key, existing.file));
+                }
+                Some(existing) => {
+                    existing.overridden.push(existing.file.clone());
+                    existing.patches.clear();
+                    existing.value = value.clone();
+                    existing.file = file.clone();
+                    existing.pack = pack;
+                }
+                None => {
+                    merged.insert(key, Definition {
+                        value: value.clone(),
+                        file: file.clone(),
+                        pack,
+                        overridden: Vec::new(),
+                        patches: Vec::new(),
+                    });
+                }
+            }
+        }
+        for (_, file, patch) in patches.iter().filter(|p| p.0 == pack) {
+            match merged.get_mut(patch.key()) {
+                Some(existing) => {
+                    patch.apply(&mut existing.value);
+                    existing.patches.push(file.clone());
+                }
+                None => problems.push(file, format!("patch for unknown {} id `{}`", D::KIND, patch.key())),
+            }
+        }
+    }
+    merged.into_values().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::SystemId;
+
+    use super::*;
+    use crate::testing::*;
+
+    fn patch(key: &str, max_stack: Option<ItemCount>, add_tags: &[&str]) -> ItemPatch {
+        ItemPatch {
+            key: key.to_string(),
+            name: None,
+            max_stack,
+            category: None,
+            add_tags: add_tags.iter().map(|t| t.to_string()).collect(),
+            remove_tags: Vec::new(),
+        }
+    }
+
+    fn keyed(key: &str, max_stack: ItemCount) -> ItemType {
+        ItemType { key: key.to_string(), ..item(0, max_stack) }
+    }
+
+    // registered once so the event readers keep their place between runs, like the reload system does
+    fn modified_check(world: &mut World) -> SystemId<(), bool> {
+        world.register_system(|mut events: DefinitionEvents| events.modified())
+    }
+
+    fn world_with_definition_events() -> World {
+        let mut world = World::new();
+        world.init_resource::<Events<AssetEvent<ItemType>>>();
+        world.init_resource::<Events<AssetEvent<ItemPatch>>>();
+        world.init_resource::<Events<AssetEvent<RecipeTemplate>>>();
+        world.init_resource::<Events<AssetEvent<RecipePatch>>>();
+        world.init_resource::<Events<AssetEvent<MachineTemplate>>>();
+        world.init_resource::<Events<AssetEvent<MachinePatch>>>();
+        world
+    }
+
+    #[test]
+    fn only_modified_files_reload() {
+        let mut world = world_with_definition_events();
+        let modified = modified_check(&mut world);
+        world.send_event(AssetEvent::<ItemType>::Added { id: AssetId::default() });
+        world.send_event(AssetEvent::<RecipePatch>::LoadedWithDependencies { id: AssetId::default() });
+        assert!(!world.run_system(modified).unwrap());
+        world.send_event(AssetEvent::<MachinePatch>::Modified { id: AssetId::default() });
+        assert!(world.run_system(modified).unwrap());
+    }
+
+    #[test]
+    fn a_change_reloads_once() {
+        let mut world = world_with_definition_events();
+        let modified = modified_check(&mut world);
+        // the events after the first modification are read too, so they don't trigger a second reload
+        world.send_event(AssetEvent::<ItemType>::Modified { id: AssetId::default() });
+        world.send_event(AssetEvent::<ItemType>::Modified { id: AssetId::default() });
+        world.send_event(AssetEvent::<RecipeTemplate>::Modified { id: AssetId::default() });
+        assert!(world.run_system(modified).unwrap());
+        assert!(!world.run_system(modified).unwrap());
+    }
+
+    #[test]
+    fn later_packs_override_and_patch() {
+        let definitions = vec![
+            (0, "items/plate.item.ron".to_string(), keyed("base:plate", 100)),
+            (0, "items/rod.item.ron".to_string(), keyed("base:rod", 100)),
+            (1, "mods/a/items/plate.item.ron".to_string(), keyed("base:plate", 50)),
+        ];
+        let patches = vec![
+            (1, "mods/a/items/rod.item.patch.ron".to_string(), patch("base:rod", Some(20), &["metal"])),
+            (2, "mods/b/items/rod.item.patch.ron".to_string(), patch("base:rod", Some(10), &[])),
+        ];
+        let mut problems = ValidationReport::default();
+        let merged = merge_sources(3, &definitions, &patches, &mut problems);
+        assert!(problems.is_empty());
+        let [plate, rod] = [&merged[0], &merged[1]];
+        assert_eq!((plate.value.max_stack, plate.pack), (50, 1));
+        assert_eq!(plate.overridden, vec!["items/plate.item.ron".to_string()]);
+        assert_eq!(rod.value.max_stack, 10);
+        assert_eq!(rod.value.tags, vec!["metal".to_string()]);
+        assert_eq!(rod.patches.len(), 2);
+    }
+
+    #[test]
+    fn overriding_drops_earlier_patches() {
+        let definitions = vec![
+            (0, "items/rod.item.ron".to_string(), keyed("base:rod", 100)),
+            (2, "mods/b/items/rod.item.ron".to_string(), keyed("base:rod", 30)),
+        ];
+        let patches = vec![(1, "mods/a/items/rod.item.patch.ron".to_string(), patch("base:rod", Some(20), &[]))];
+        let mut problems = ValidationReport::default();
+        let merged = merge_sources(3, &definitions, &patches, &mut problems);
+        assert_eq!(merged[0].value.max_stack, 30);
+        assert!(merged[0].patches.is_empty());
+    }
+
+    #[test]
+    fn duplicates_and_unknown_patches_are_problems() {
+        let definitions = vec![
+            (0, "items/a.item.ron".to_string(), keyed("base:plate", 100)),
+            (0, "items/b.item.ron".to_string(), keyed("base:plate", 100)),
+        ];
+        let patches = vec![(0, "items/gear.item.patch.ron".to_string(), patch("base:gear", Some(1), &[]))];
+        let mut problems = ValidationReport::default();
+        let merged = merge_sources(1, &definitions, &patches, &mut problems);
+        assert_eq!(merged.len(), 1);
+        assert_eq!(problems.problems.len(), 2);
+    }
+}
@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+
+use bevy::prelude::*;
+use bevy::time::TimeUpdateStrategy;
+
+use crate::state::AppState;
+use crate::machine::*;
+use crate::simulation::*;
+use crate::validation::*;
+use crate::save::*;
+
+// runs the game for a fixed number of ticks without a window, then prints what the factory made
+pub struct HeadlessPlugin {
+    pub ticks: u64,
+    pub save: Option<PathBuf>,
+}
+
+impl Plugin for HeadlessPlugin {
+    fn build(&self, app: &mut App) {
+        // frames run as fast as possible, each one advancing the game by exactly one tick
+        app.insert_resource(TimeUpdateStrategy::ManualDuration(TICK_LENGTH));
+        app.insert_resource(HeadlessRun { ticks: 0, limit: self.ticks, save: self.save.clone() });
+        app.add_systems(SimTick, count_ticks);
+        app.add_systems(
+            Last,
+            finish_run.run_if(in_state(AppState::InGame)).before(save_game)
+        );
+        app.add_systems(
+            OnEnter(AppState::AssetError),
+            fail_run
+        );
+    }
+}
+
+// ticks are counted from the start of the run, a loaded save can start at any tick
+#[derive(Resource, Debug)]
+pub struct HeadlessRun {
+    pub ticks: u64,
+    pub limit: u64,
+    pub save: Option<PathBuf>,
+}
+
+fn count_ticks(mut run: ResMut<HeadlessRun>) {
+    run.ticks += 1;
+}
+
+fn finish_run(
+    run: Res<HeadlessRun>,
+    factory: FactoryState,
+    stats: Res<ProductionStats>,
+    q: Query<&MachineState, With<Machine>>,
+    mut saves: EventWriter<SaveGame>,
+    mut exit: EventWriter<AppExit>,
+) {
+    if run.ticks < run.limit {
+        return;
+    }
+    println!("Production after {} ticks ({:.1}s of game time):", factory.tick.0, factory.tick.elapsed().as_secs_f64());
+    stats.print();
+    println!("Machines:");
+    for state in q.iter() {
+        println!("  {:?}", state);
+    }
+    println!("State checksum: {:016x}", factory.checksum());
+    if let Some(path) = &run.save {
+        saves.send(SaveGame(path.clone()));
+    }
+    exit.send(AppExit::Success);
+}
+
+fn fail_run(
+    report: Res<ValidationReport>,
+    mut exit: EventWriter<AppExit>,
+) {
+    eprintln!("Can't run, found {} problem(s) in the asset files", report.problems.len());
+    exit.send(AppExit::error());
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    use bevy::ecs::system::RunSystemOnce;
+    use bevy::state::app::StatesPlugin;
+
+    use super::*;
+    use crate::scenario::*;
+
+    // the game as `--headless` runs it, on the repository's assets
+    fn headless_app(ticks: u64, load: Option<&Path>, save: Option<&Path>) -> App {
+        let mut app = App::new();
+        app.add_plugins((
+            MinimalPlugins,
+            bevy::asset::AssetPlugin {
+                file_path: concat!(env!("CARGO_MANIFEST_DIR"), "/assets").to_string(),
+                ..default()
+            },
+            StatesPlugin,
+            HeadlessPlugin { ticks, save: save.map(Path::to_path_buf) },
+            SimulationPlugin,
+            ScenarioPlugin { path: DEFAULT_SCENARIO.to_string() },
+            SavePlugin { load_on_start: load.map(Path::to_path_buf) },
+        ));
+        app
+    }
+
+    // update until the run finishes, then return the checksum of the final state
+    fn finish(mut app: App) -> u64 {
+        // what `App::run` does before the first update
+        app.finish();
+        app.cleanup();
+        let started = Instant::now();
+        while app.should_exit().is_none() {
+            assert!(started.elapsed() < Duration::from_secs(60), "the run didn't finish");
+            app.update();
+        }
+        assert_eq!(app.should_exit(), Some(AppExit::Success));
+        app.world_mut().run_system_once(|factory: FactoryState| factory.checksum())
+    }
+
+    #[test]
+    fn runs_of_the_same_length_end_in_the_same_state() {
+        let first = finish(headless_app(240, None, None));
+        let second = finish(headless_app(240, None, None));
+        // several ticks a frame, as when fast forwarding or on a slow frame
+        let mut batched = headless_app(240, None, None);
+        batched.insert_resource(TimeUpdateStrategy::ManualDuration(TICK_LENGTH * 4));
+        let batched = finish(batched);
+
+        assert_eq!(first, second);
+        assert_eq!(first, batched);
+    }
+
+    #[test]
+    fn runs_from_the_same_save_end_in_the_same_state() {
+        let save = std::env::temp_dir().join(format!("bevy-automation-determinism-{}.save.ron", std::process::id()));
+        finish(headless_app(90, None, Some(&save)));
+
+        let first = finish(headless_app(240, Some(&save), None));
+        let second = finish(headless_app(240, Some(&save), None));
+        // several ticks a frame, as when fast forwarding or on a slow frame
+        let mut batched = headless_app(240, Some(&save), None);
+        batched.insert_resource(TimeUpdateStrategy::ManualDuration(TICK_LENGTH * 4));
+        let batched = finish(batched);
+        let _ = std::fs::remove_file(&save);
+
+        assert_eq!(first, second);
+        assert_eq!(first, batched);
+    }
+}
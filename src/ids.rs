@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+
+use bevy::prelude::*;
+
+// namespace of the base game, ids written without one get their pack's namespace, e.g. `iron_plate` -> `base:iron_plate`
+pub const DEFAULT_NAMESPACE: &str = "base";
+
+// add a namespace to an id that doesn't have one yet
+pub fn qualify(id: &str, namespace: &str) -> String {
+    if id.contains(':') {
+        id.to_string()
+    } else {
+        format!("{}:{}", namespace, id)
+    }
+}
+
+// maps namespaced string ids to compact numeric ids used at runtime
+// numeric ids are handed out in order and only stay the same within one run
+#[derive(Default, Clone, Debug)]
+pub struct Interner {
+    ids: HashMap<String, u16>,
+    keys: Vec<String>,
+}
+
+impl Interner {
+    // numeric id for a key, assigning a new one if the key hasn't been seen before
+    pub fn intern(&mut self, key: &str) -> Option<u16> {
+        if let Some(id) = self.ids.get(key) {
+            return Some(*id);
+        }
+        let id = u16::try_from(self.keys.len()).ok()?;
+        self.ids.insert(key.to_string(), id);
+        self.keys.push(key.to_string());
+        Some(id)
+    }
+
+    pub fn get(&self, key: &str) -> Option<u16> {
+        self.ids.get(key).copied()
+    }
+
+    pub fn key(&self, id: u16) -> Option<&str> {
+        self.keys.get(id as usize).map(|k| k.as_str())
+    }
+}
+
+#[derive(Resource, Default, Clone, Debug)]
+pub struct IdRegistry {
+    pub items: Interner,
+    pub recipes: Interner,
+    pub machines: Interner,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_without_a_namespace_get_one() {
+        assert_eq!(qualify("iron_plate", DEFAULT_NAMESPACE), "base:iron_plate");
+        assert_eq!(qualify("iron_plate", "gears"), "gears:iron_plate");
+        assert_eq!(qualify("base:iron_plate", "gears"), "base:iron_plate");
+    }
+
+    #[test]
+    fn ids_are_handed_out_in_order() {
+        let mut interner = Interner::default();
+        assert_eq!(interner.intern("base:plate"), Some(0));
+        assert_eq!(interner.intern("base:rod"), Some(1));
+        assert_eq!(interner.intern("base:plate"), Some(0));
+        assert_eq!(interner.get("base:rod"), Some(1));
+        assert_eq!(interner.get("base:gear"), None);
+        assert_eq!(interner.key(1), Some("base:rod"));
+        assert_eq!(interner.key(2), None);
+    }
+
+    #[test]
+    fn interning_fails_once_the_ids_run_out() {
+        let mut interner = Interner::default();
+        for i in 0..=u16::MAX {
+            assert_eq!(interner.intern(&format!("test:{}", i)), Some(i));
+        }
+        assert_eq!(interner.intern("test:one_too_many"), None);
+        assert_eq!(interner.get("test:one_too_many"), None);
+        // keys that already have an id still get it
+        assert_eq!(interner.intern("test:7"), Some(7));
+    }
+}
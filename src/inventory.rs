@@ -1,142 +1,1003 @@
-use std::cmp::min;
+use std::any::Any;
+use std::cmp::{min, Ordering};
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::time::Duration;
 
 use bevy::prelude::*;
 
+use crate::asset::*;
+use crate::ids::*;
+use crate::state::AppState;
+use crate::simulation::*;
 use crate::item::*;
+use crate::itemset::*;
+use crate::recipe::*;
+use crate::reservation::*;
 
-#[derive(Component)]
+pub struct InventoryPlugin;
+
+impl Plugin for InventoryPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<ItemsInserted>()
+            .add_event::<ItemsExtracted>()
+            .add_event::<InventoryFull>()
+            .add_event::<InventoryEmptied>();
+        app.add_systems(
+            SimTick,
+            (
+                (expire_reservations::<Inventory>, expire_reservations::<Storage>).chain(),
+                (emit_inventory_events::<Inventory>, emit_inventory_events::<Storage>).chain(),
+            ).chain()
+        );
+        app.add_systems(
+            Update,
+            (refresh_item_types::<Inventory>, refresh_item_types::<Storage>)
+                .after(reload_definitions)
+                .run_if(in_state(AppState::InGame))
+        );
+        app.add_systems(Update, log_inventory_events.run_if(in_state(AppState::InGame)));
+    }
+}
+
+// which component of an entity an inventory event refers to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ContainerKind {
+    Inventory,
+    Storage,
+    Input,
+    Output,
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct ItemsInserted {
+    pub entity: Entity,
+    pub container: ContainerKind,
+    pub item_id: u16,
+    pub delta: ItemCount,
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct ItemsExtracted {
+    pub entity: Entity,
+    pub container: ContainerKind,
+    pub item_id: u16,
+    pub delta: ItemCount,
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct InventoryFull {
+    pub entity: Entity,
+    pub container: ContainerKind,
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct InventoryEmptied {
+    pub entity: Entity,
+    pub container: ContainerKind,
+}
+
+// item amounts that changed since the container's events were last sent
+#[derive(Clone, Default, Debug)]
+pub struct ChangeLog(Vec<(u16, i128)>);
+
+impl ChangeLog {
+    pub fn record(&mut self, item_id: u16, delta: i128) {
+        self.0.push((item_id, delta));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    // net change per item id, ordered by id, with changes that cancelled out removed
+    pub fn drain(&mut self) -> Vec<(u16, i128)> {
+        let mut net = Vec::<(u16, i128)>::new();
+        for (item_id, delta) in self.0.drain(..) {
+            match net.iter_mut().find(|(id, _)| *id == item_id) {
+                Some((_, total)) => *total += delta,
+                None => net.push((item_id, delta)),
+            }
+        }
+        net.retain(|(_, delta)| *delta != 0);
+        net.sort_by_key(|(id, _)| *id);
+        net
+    }
+}
+
+// container components that report their changes as events
+pub trait InventoryComponent: Component + ItemContainer {
+    const KIND: ContainerKind;
+}
+
+impl InventoryComponent for Inventory {
+    const KIND: ContainerKind = ContainerKind::Inventory;
+}
+
+impl InventoryComponent for Storage {
+    const KIND: ContainerKind = ContainerKind::Storage;
+}
+
+pub fn emit_inventory_events<C: InventoryComponent>(
+    mut q: Query<(Entity, &mut C), Changed<C>>,
+    mut inserted: EventWriter<ItemsInserted>,
+    mut extracted: EventWriter<ItemsExtracted>,
+    mut full: EventWriter<InventoryFull>,
+    mut emptied: EventWriter<InventoryEmptied>,
+) {
+    for (entity, mut container) in q.iter_mut() {
+        if container.change_log().is_empty() {
+            continue;
+        }
+        let changes = container.change_log_mut().drain();
+        let mut any_inserted = false;
+        let mut any_extracted = false;
+        for (item_id, delta) in changes.into_iter() {
+            let amount = ItemCount::try_from(delta.unsigned_abs()).unwrap_or(ItemCount::MAX);
+            if delta > 0 {
+                any_inserted = true;
+                inserted.send(ItemsInserted { entity, container: C::KIND, item_id, delta: amount });
+            } else {
+                any_extracted = true;
+                extracted.send(ItemsExtracted { entity, container: C::KIND, item_id, delta: amount });
+            }
+        }
+        if any_inserted && container.free_slots() == 0 {
+            full.send(InventoryFull { entity, container: C::KIND });
+        }
+        if any_extracted && container.is_empty() {
+            emptied.send(InventoryEmptied { entity, container: C::KIND });
+        }
+    }
+}
+
+// every item that moves is an event, so they're only logged at debug level
+fn log_inventory_events(
+    mut inserted: EventReader<ItemsInserted>,
+    mut extracted: EventReader<ItemsExtracted>,
+    mut full: EventReader<InventoryFull>,
+    mut emptied: EventReader<InventoryEmptied>,
+    registry: Res<IdRegistry>,
+) {
+    let item = |id| registry.items.key(id).unwrap_or("<unknown item>");
+    for event in inserted.read() {
+        debug!("{:?} {:?}: +{} {}", event.entity, event.container, event.delta, item(event.item_id));
+    }
+    for event in extracted.read() {
+        debug!("{:?} {:?}: -{} {}", event.entity, event.container, event.delta, item(event.item_id));
+    }
+    for event in full.read() {
+        debug!("{:?} {:?} is full", event.entity, event.container);
+    }
+    for event in emptied.read() {
+        debug!("{:?} {:?} is empty", event.entity, event.container);
+    }
+}
+
+// lets transactions work on copies of containers they only know as `dyn ItemContainer`
+pub trait ContainerCopy {
+    fn boxed_copy(&self) -> Box<dyn ItemContainer>;
+    // take over a copy made by `boxed_copy`, copies of other container types are ignored
+    fn replace_with(&mut self, copy: Box<dyn ItemContainer>);
+    fn into_any(self: Box<Self>) -> Box<dyn Any>;
+}
+
+impl<T: ItemContainer + Clone + 'static> ContainerCopy for T {
+    fn boxed_copy(&self) -> Box<dyn ItemContainer> {
+        Box::new(self.clone())
+    }
+
+    fn replace_with(&mut self, copy: Box<dyn ItemContainer>) {
+        match copy.into_any().downcast::<T>() {
+            Ok(copy) => *self = *copy,
+            Err(_) => error!("Tried to replace a container with a copy of a different type"),
+        }
+    }
+
+    fn into_any(self: Box<Self>) -> Box<dyn Any> {
+        self
+    }
+}
+
+// operations shared by every item holder, so systems can work on any container component
+pub trait ItemContainer: ContainerCopy {
+    // insert stacks, return whatever did not fit
+    // `owner` is the entity inserting, slots reserved by other entities are left alone
+    fn insert_as(&mut self, owner: Option<Entity>, stacks: &[ItemStack]) -> Vec<ItemStack>;
+    // extract all of the stacks, or nothing if any are missing
+    fn extract(&mut self, stacks: &[ItemStack]) -> bool;
+    // current contents, not necessarily split by slot
+    fn stacks(&self) -> Vec<ItemStack>;
+    // total number of slots
+    fn capacity(&self) -> usize;
+    fn used_slots(&self) -> usize;
+    fn reservations(&self) -> &Reservations;
+    fn reservations_mut(&mut self) -> &mut Reservations;
+    fn change_log(&self) -> &ChangeLog;
+    fn change_log_mut(&mut self) -> &mut ChangeLog;
+    // replace the held item types with their current definitions, after they were reloaded
+    fn refresh_item_types(&mut self, item_types: &ItemTypeList);
+
+    fn insert(&mut self, stacks: &[ItemStack]) -> Vec<ItemStack> {
+        self.insert_as(None, stacks)
+    }
+
+    // empty slots the stacks would take up once inserted, None if they don't all fit
+    fn slots_needed(&self, owner: Option<Entity>, stacks: &[ItemStack]) -> Option<usize> {
+        let mut copy = self.boxed_copy();
+        if !copy.insert_as(owner, stacks).is_empty() {
+            return None;
+        }
+        Some(copy.used_slots().saturating_sub(self.used_slots()))
+    }
+
+    fn contains(&self, stacks: &[ItemStack]) -> bool {
+        for stack in stacks.iter() {
+            let needed = saturating_sum(
+                stacks.iter()
+                    .filter(|s| s.item_type == stack.item_type)
+                    .map(|s| s.size)
+            );
+            if self.count(&stack.item_type) < needed {
+                return false;
+            }
+        }
+        true
+    }
+
+    fn free_slots(&self) -> usize {
+        self.capacity().saturating_sub(self.used_slots())
+    }
+
+    // free slots that are not reserved by anyone but `owner`
+    fn available_slots(&self, owner: Option<Entity>) -> usize {
+        self.free_slots().saturating_sub(self.reservations().reserved_slots(owner))
+    }
+
+    fn count(&self, item_type: &ItemType) -> ItemCount {
+        saturating_sum(
+            self.stacks()
+                .iter()
+                .filter(|s| s.item_type == *item_type)
+                .map(|s| s.size)
+        )
+    }
+
+    fn is_empty(&self) -> bool {
+        self.used_slots() == 0
+    }
+
+    // keep empty slots free for `owner`, false if there aren't enough that nobody reserved
+    fn reserve_slots(&mut self, owner: Entity, slots: usize, expires_at: Option<Duration>) -> bool {
+        if self.available_slots(None) < slots {
+            return false;
+        }
+        self.reservations_mut().push(owner, slots, expires_at);
+        true
+    }
+
+    fn cancel_reservations(&mut self, owner: Entity) {
+        self.reservations_mut().cancel_all(owner);
+    }
+}
+
+pub fn refresh_item_types<C: Component + ItemContainer>(
+    mut reloaded: EventReader<DefinitionsReloaded>,
+    item_types: Res<ItemTypeList>,
+    mut q: Query<&mut C>,
+) {
+    if reloaded.is_empty() {
+        return;
+    }
+    reloaded.clear();
+    for mut container in q.iter_mut() {
+        container.refresh_item_types(&item_types);
+    }
+}
+
+pub fn expire_reservations<C: Component + ItemContainer>(
+    tick: Res<CurrentTick>,
+    mut q: Query<&mut C>,
+) {
+    let now = tick.elapsed();
+    for mut container in q.iter_mut() {
+        if container.reservations().has_expired(now) {
+            let expired = container.reservations_mut().expire(now);
+            debug!("{} reservation(s) timed out", expired);
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub enum ItemFilter {
+    #[default]
+    Any,
+    Items(HashSet<u16>),
+    Tags(HashSet<String>),
+    // accepts anything one of the filters accepts
+    AnyOf(Vec<ItemFilter>),
+}
+
+impl ItemFilter {
+    pub fn from_recipe_inputs(recipe: &Recipe) -> Self {
+        let items = ItemFilter::Items(recipe.inputs.iter().map(|s| s.item_type.id).collect());
+        if recipe.tag_inputs.is_empty() {
+            return items;
+        }
+        let tags = ItemFilter::Tags(recipe.tag_inputs.iter().map(|i| i.tag.clone()).collect());
+        ItemFilter::AnyOf(vec![items, tags])
+    }
+
+    pub fn accepts(&self, item_type: &ItemType) -> bool {
+        match self {
+            ItemFilter::Any => true,
+            ItemFilter::Items(ids) => ids.contains(&item_type.id),
+            ItemFilter::Tags(tags) => tags.iter().any(|t| item_type.has_tag(t)),
+            ItemFilter::AnyOf(filters) => filters.iter().any(|f| f.accepts(item_type)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortMode {
+    #[default]
+    Id,
+    Name,
+    // largest stacks first
+    Count,
+    // by first tag, untagged items last
+    Tag,
+}
+
+impl SortMode {
+    pub fn next(self) -> Self {
+        match self {
+            SortMode::Id => SortMode::Name,
+            SortMode::Name => SortMode::Count,
+            SortMode::Count => SortMode::Tag,
+            SortMode::Tag => SortMode::Id,
+        }
+    }
+}
+
+pub fn sort_stacks(stacks: &mut [ItemStack], mode: SortMode) {
+    stacks.sort_by(|a, b| {
+        let order = match mode {
+            SortMode::Id => a.item_type.id.cmp(&b.item_type.id),
+            SortMode::Name => a.item_type.name.cmp(&b.item_type.name),
+            SortMode::Count => b.size.cmp(&a.size),
+            SortMode::Tag => match (a.item_type.tags.first(), b.item_type.tags.first()) {
+                (Some(a_tag), Some(b_tag)) => a_tag.cmp(b_tag),
+                (Some(_), None) => Ordering::Less,
+                (None, Some(_)) => Ordering::Greater,
+                (None, None) => Ordering::Equal,
+            },
+        };
+        order
+            .then(a.item_type.id.cmp(&b.item_type.id))
+            .then(b.size.cmp(&a.size))
+    });
+}
+
+#[derive(Component, Clone)]
 pub struct Inventory {
-    pub stacks: Vec<ItemStack>,
-    pub slots: u16,
+    pub slots: Vec<Option<ItemStack>>,
+    pub filter: ItemFilter,
+    pub slot_filters: Vec<ItemFilter>,
+    pub reservations: Reservations,
+    pub changes: ChangeLog,
+    // merge stacks after every add or remove, turn off for inventories where the player arranges slots
+    pub auto_compact: bool,
 }
 
 impl Default for Inventory {
     fn default() -> Self {
-        Inventory {
-            stacks: Vec::<ItemStack>::new(),
-            slots: 1,
+        Inventory::new(1)
+    }
+}
+
+impl Display for Inventory {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[")?;
+        for (i, slot) in self.slots.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            match slot {
+                Some(stack) => write!(f, "{}", stack)?,
+                None => write!(f, "empty")?,
+            }
         }
+        write!(f, "]")
     }
 }
 
 impl Inventory {
-    pub fn contains(&self, stacks: &[ItemStack]) -> bool {
-        for stack in stacks.iter() {
-            let mut cont = true;
-            for s in self.stacks.iter() {
-                if s > stack {
-                    cont = false;
-                    break;
+    pub fn new(slots: usize) -> Self {
+        Inventory {
+            slots: vec![None; slots],
+            filter: ItemFilter::Any,
+            slot_filters: vec![ItemFilter::Any; slots],
+            reservations: Reservations::default(),
+            changes: ChangeLog::default(),
+            auto_compact: true,
+        }
+    }
+
+    pub fn set_filter(&mut self, filter: ItemFilter) {
+        self.filter = filter;
+    }
+
+    pub fn set_slot_filter(&mut self, index: usize, filter: ItemFilter) -> bool {
+        let Some(slot_filter) = self.slot_filters.get_mut(index) else {
+            return false;
+        };
+        *slot_filter = filter;
+        true
+    }
+
+    // whether both the inventory filter and the slot's own filter allow this item type
+    pub fn accepts(&self, index: usize, item_type: &ItemType) -> bool {
+        self.filter.accepts(item_type)
+            && self.slot_filters.get(index).is_some_and(|f| f.accepts(item_type))
+    }
+
+    pub fn size(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn slot(&self, index: usize) -> Option<&ItemStack> {
+        self.slots.get(index).and_then(|s| s.as_ref())
+    }
+
+    pub fn stacks(&self) -> impl Iterator<Item = &ItemStack> {
+        self.slots.iter().flatten()
+    }
+
+    pub fn used_slots(&self) -> usize {
+        self.stacks().count()
+    }
+
+    // total amount of an item type across all slots
+    pub fn count(&self, item_type: &ItemType) -> ItemCount {
+        saturating_sum(
+            self.stacks()
+                .filter(|s| s.item_type == *item_type)
+                .map(|s| s.size)
+        )
+    }
+
+    // put as much of the stack as possible into one slot, return leftovers
+    pub fn insert_into_slot(&mut self, index: usize, stack: ItemStack) -> Option<ItemStack> {
+        if !self.accepts(index, &stack.item_type) {
+            return Some(stack);
+        }
+        let Some(slot) = self.slots.get_mut(index) else {
+            return Some(stack);
+        };
+        let mut leftover = stack;
+        let moved = match slot {
+            Some(s) => s.merge(&mut leftover),
+            None => {
+                let mut new_stack = ItemStack::new(leftover.item_type.clone(), 0);
+                let moved = new_stack.merge(&mut leftover);
+                if new_stack.size > 0 {
+                    *slot = Some(new_stack);
                 }
+                moved
             }
-            if cont {
-                return false;
-            }
+        };
+        match moved {
+            Ok(amount) if amount > 0 => self.changes.record(leftover.item_type.id, amount as i128),
+            Ok(_) => (),
+            Err(e) => debug!("Couldn't insert into slot {}: {}", index, e),
         }
+        if leftover.size == 0 { None } else { Some(leftover) }
+    }
+
+    // take up to `amount` items out of one slot
+    pub fn extract_from_slot(&mut self, index: usize, amount: ItemCount) -> Option<ItemStack> {
+        let slot = self.slots.get_mut(index)?;
+        let stack = slot.as_mut()?;
+        let size = min(stack.size, amount);
+        if size == 0 {
+            return None;
+        }
+        let extracted = stack.split(size).ok()?;
+        if stack.size == 0 {
+            *slot = None;
+        }
+        self.changes.record(extracted.item_type.id, -(size as i128));
+        Some(extracted)
+    }
+
+    // only swaps if each slot accepts the stack it would get
+    pub fn swap_slots(&mut self, a: usize, b: usize) -> bool {
+        if a >= self.size() || b >= self.size() {
+            return false;
+        }
+        if self.slot(a).is_some_and(|s| !self.accepts(b, &s.item_type))
+            || self.slot(b).is_some_and(|s| !self.accepts(a, &s.item_type))
+        {
+            return false;
+        }
+        self.slots.swap(a, b);
         true
     }
 
+    // move `amount` items from a slot into the first empty slot that accepts them, return the new slot index
+    // the new stack takes up a free slot, so there has to be one that nobody reserved
+    pub fn split_slot(&mut self, index: usize, amount: ItemCount) -> Option<usize> {
+        let stack = self.slot(index)?;
+        if amount == 0 || amount >= stack.size || self.available_slots(None) == 0 {
+            return None;
+        }
+        let target = (0..self.size()).find(|i| self.slots[*i].is_none() && self.accepts(*i, &stack.item_type))?;
+        let split = self.extract_from_slot(index, amount)?;
+        if let Some(leftover) = self.insert_into_slot(target, split) {
+            // the target was empty and accepts the type, this only happens if the split is over the max stack
+            self.insert_into_slot(index, leftover);
+        }
+        Some(target)
+    }
+
     pub fn remove(&mut self, stacks: &[ItemStack]) -> bool {
-        if !self.contains(stacks) {
+        if !ItemContainer::contains(self, stacks) {
             return false;
         }
         for r in stacks.iter() {
-            for s in self.stacks.iter_mut() {
-                if r.item_type == s.item_type {
-                    s.size -= r.size;
+            let mut to_remove = r.size;
+            // take from the last slots first so the front of the inventory stays filled
+            for i in (0..self.size()).rev() {
+                if to_remove == 0 {
                     break;
                 }
+                if self.slot(i).is_some_and(|s| s.item_type == r.item_type) {
+                    if let Some(removed) = self.extract_from_slot(i, to_remove) {
+                        to_remove -= removed.size;
+                    }
+                }
             }
         }
+        if self.auto_compact {
+            self.compact();
+        }
         true
     }
 
-    pub fn used_full_slots(&self) -> u16 {
-        let mut used_slots: u16 = 0;
-        for stack in self.stacks.iter() {
-            used_slots += stack.needed_full_slots();
-        }
-        used_slots
+    pub fn add(&mut self, stacks: &[ItemStack]) -> Vec<ItemStack> {
+        self.add_as(None, stacks)
     }
 
-    pub fn used_partial_slots(&self) -> u16 {
-        let mut used_slots: u16 = 0;
-        for stack in self.stacks.iter() {
-            used_slots += stack.needed_partial_slots();
+    pub fn add_as(&mut self, owner: Option<Entity>, stacks: &[ItemStack]) -> Vec<ItemStack> {
+        let empty_slots = self.available_slots(owner);
+        let mut used_empty_slots: usize = 0;
+        let mut leftovers = Vec::<ItemStack>::new();
+        for a in stacks.iter() {
+            if a.size == 0 {
+                continue;
+            }
+            let mut remaining = Some(a.clone());
+            // top up partial stacks of the same type before using empty slots
+            for i in 0..self.size() {
+                let Some(stack) = remaining.take() else { break };
+                remaining = if self.slot(i).is_some_and(|s| s.item_type == stack.item_type) {
+                    self.insert_into_slot(i, stack)
+                } else {
+                    Some(stack)
+                };
+            }
+            for i in 0..self.size() {
+                let Some(stack) = remaining.take() else { break };
+                remaining = if self.slots[i].is_none() && used_empty_slots < empty_slots {
+                    let leftover = self.insert_into_slot(i, stack);
+                    if self.slots[i].is_some() {
+                        used_empty_slots += 1;
+                    }
+                    leftover
+                } else {
+                    Some(stack)
+                };
+            }
+            if let Some(leftover) = remaining {
+                if leftover.size > 0 {
+                    leftovers.push(leftover);
+                }
+            }
+        }
+        if let Some(owner) = owner {
+            self.reservations.consume_slots(owner, used_empty_slots);
         }
-        used_slots
+        if self.auto_compact {
+            self.compact();
+        }
+        leftovers
     }
 
-    pub fn can_fit(&self, stacks: &[ItemStack]) -> bool {
-        let mut needed_slots: u16 = 0;
-        for stack in stacks.iter() {
-            let mut has_type = false;
-            for s in self.stacks.iter() {
-                if s.item_type == stack.item_type {
-                    has_type = true;
-                    needed_slots += (s.clone() + stack.clone()).needed_slots();
+    // drop empty stacks and merge partial stacks of the same type into the earliest slot holding that type
+    pub fn compact(&mut self) {
+        for slot in self.slots.iter_mut() {
+            if slot.as_ref().is_some_and(|s| s.size == 0) {
+                *slot = None;
+            }
+        }
+        for i in 0..self.size() {
+            for j in (i + 1)..self.size() {
+                let Some(target) = self.slot(i) else { break };
+                // a filter changed after the stack was put here, don't pull more of it in
+                if target.size >= target.item_type.max_stack || !self.accepts(i, &target.item_type) {
+                    break;
+                }
+                if !self.slot(j).is_some_and(|s| s.item_type == target.item_type) {
+                    continue;
+                }
+                let room = target.room();
+                let Some(moved) = self.extract_from_slot(j, room) else { continue };
+                if let Some(leftover) = self.insert_into_slot(i, moved) {
+                    self.put_back(j, leftover);
                 }
             }
-            if !has_type {
-                needed_slots += stack.needed_slots();
+        }
+    }
+
+    // return items that were just taken out of a slot, its filter may not accept them anymore
+    fn put_back(&mut self, index: usize, stack: ItemStack) {
+        let slot = &mut self.slots[index];
+        let merged = match slot {
+            Some(s) => s.checked_add(&stack),
+            None => Ok(stack.clone()),
+        };
+        match merged {
+            Ok(merged) => {
+                self.changes.record(stack.item_type.id, stack.size as i128);
+                *slot = Some(merged);
             }
+            Err(e) => error!("Couldn't put {} back into slot {}: {}", stack, index, e),
         }
-        needed_slots <= self.slots
     }
 
-    pub fn add(&mut self, stacks: &[ItemStack]) -> Vec<ItemStack> {
-        let mut free_slots = self.slots - self.used_full_slots();
-        let mut to_add = stacks.to_owned();
-        for a in to_add.iter_mut() {
-            let item_type = a.item_type.clone();
-            let mut has_type = false;
-            for s in self.stacks.iter_mut() {
-                if item_type == s.item_type {
-                    has_type = true;
-                    if free_slots == 0 {
-                        return to_add;
+    // compact, then lay the stacks out in order from the first slot that accepts them
+    // stacks that don't fit anywhere else that way stay in the slot they are in
+    pub fn sort(&mut self, mode: SortMode) {
+        self.compact();
+        let mut pinned = vec![false; self.size()];
+        loop {
+            let mut layout: Vec<Option<ItemStack>> = (0..self.size())
+                .map(|i| if pinned[i] { self.slots[i].clone() } else { None })
+                .collect();
+            let mut stacks: Vec<ItemStack> = (0..self.size())
+                .filter(|i| !pinned[*i])
+                .filter_map(|i| self.slots[i].clone())
+                .collect();
+            sort_stacks(&mut stacks, mode);
+            let mut unplaced = None;
+            for stack in stacks.into_iter() {
+                match (0..self.size()).find(|i| layout[*i].is_none() && self.accepts(*i, &stack.item_type)) {
+                    Some(target) => layout[target] = Some(stack),
+                    None => {
+                        unplaced = Some(stack);
+                        break;
                     }
-                    while free_slots > 0 && a.size > 0 {
-                        let remainder = item_type.max_stack - (s.size % item_type.max_stack);
-                        if remainder == item_type.max_stack {
-                            // consume a new slot to add items
-                            free_slots -= 1;
-                        }
-                        let amount_to_add = min(remainder, a.size);
-                        s.size += amount_to_add;
-                        a.size -= amount_to_add;
-                    }
-                    break;
                 }
             }
-            if !has_type {
-                let needed_slots = min(free_slots, a.needed_slots());
-                let size = min(a.size, needed_slots * item_type.max_stack);
-                let stack = ItemStack { item_type, size };
-                self.stacks.push(stack);
-                a.size -= size;
-                free_slots -= needed_slots;
-                if free_slots == 0 {
-                    break;
-                }
+            let Some(unplaced) = unplaced else {
+                self.slots = layout;
+                return;
+            };
+            // pin one stack like it and lay out the rest around it, at worst everything stays where it is
+            let Some(index) = (0..self.size()).find(|i| {
+                !pinned[*i] && self.slot(*i).is_some_and(|s| s.item_type == unplaced.item_type && s.size == unplaced.size)
+            }) else {
+                return;
+            };
+            pinned[index] = true;
+        }
+    }
+}
+
+impl ItemContainer for Inventory {
+    fn insert_as(&mut self, owner: Option<Entity>, stacks: &[ItemStack]) -> Vec<ItemStack> {
+        self.add_as(owner, stacks)
+    }
+
+    fn extract(&mut self, stacks: &[ItemStack]) -> bool {
+        self.remove(stacks)
+    }
+
+    fn stacks(&self) -> Vec<ItemStack> {
+        Inventory::stacks(self).cloned().collect()
+    }
+
+    fn capacity(&self) -> usize {
+        self.size()
+    }
+
+    fn used_slots(&self) -> usize {
+        Inventory::used_slots(self)
+    }
+
+    fn reservations(&self) -> &Reservations {
+        &self.reservations
+    }
+
+    fn reservations_mut(&mut self) -> &mut Reservations {
+        &mut self.reservations
+    }
+
+    fn change_log(&self) -> &ChangeLog {
+        &self.changes
+    }
+
+    fn change_log_mut(&mut self) -> &mut ChangeLog {
+        &mut self.changes
+    }
+
+    fn count(&self, item_type: &ItemType) -> ItemCount {
+        Inventory::count(self, item_type)
+    }
+
+    // stacks over a lowered max stack are left as they are, they shrink as items are taken out
+    fn refresh_item_types(&mut self, item_types: &ItemTypeList) {
+        for stack in self.slots.iter_mut().flatten() {
+            if let Some(item_type) = item_types.get(stack.item_type.id) {
+                stack.item_type = item_type.clone();
             }
         }
-        for i in (0..to_add.len()).rev() {
-            if to_add[i].size == 0 {
-                to_add.remove(i);
+    }
+}
+
+// chosen per storage in scenarios and kept in saves
+#[derive(serde::Serialize, serde::Deserialize, Clone, Copy, Default, PartialEq, Eq, Hash, Debug)]
+pub enum StorageBackend {
+    #[default]
+    Slotted,
+    Hashed,
+}
+
+// storage component for entities that don't need a specific backend, e.g. warehouses
+#[derive(Component, Clone)]
+pub enum Storage {
+    Slotted(Inventory),
+    Hashed(ItemSet),
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::new(StorageBackend::default(), 1)
+    }
+}
+
+impl Storage {
+    pub fn new(backend: StorageBackend, slots: usize) -> Self {
+        match backend {
+            StorageBackend::Slotted => Storage::Slotted(Inventory::new(slots)),
+            StorageBackend::Hashed => Storage::Hashed(ItemSet::new(slots)),
+        }
+    }
+
+    pub fn backend(&self) -> StorageBackend {
+        match self {
+            Storage::Slotted(_) => StorageBackend::Slotted,
+            Storage::Hashed(_) => StorageBackend::Hashed,
+        }
+    }
+
+    // sort the contents and return them in their new order
+    // hashed storages keep no order, so only the returned stacks are sorted
+    pub fn sort(&mut self, mode: SortMode) -> Vec<ItemStack> {
+        match self {
+            Storage::Slotted(inventory) => {
+                inventory.sort(mode);
+                inventory.stacks().cloned().collect()
             }
+            Storage::Hashed(set) => set.sorted_stacks(mode),
         }
-        to_add
     }
 
-    pub fn add_strict(&mut self, stacks: &[ItemStack]) -> bool {
-        if self.can_fit(stacks) {
-            let remainder = self.add(stacks);
-            if !remainder.is_empty() {
-                panic!("Thought stacks would fit in inventory, but they didnt! {} stacks were left.", remainder.len());
+    pub fn container(&self) -> &dyn ItemContainer {
+        match self {
+            Storage::Slotted(inventory) => inventory,
+            Storage::Hashed(set) => set,
+        }
+    }
+
+    pub fn container_mut(&mut self) -> &mut dyn ItemContainer {
+        match self {
+            Storage::Slotted(inventory) => inventory,
+            Storage::Hashed(set) => set,
+        }
+    }
+}
+
+impl ItemContainer for Storage {
+    fn insert_as(&mut self, owner: Option<Entity>, stacks: &[ItemStack]) -> Vec<ItemStack> {
+        self.container_mut().insert_as(owner, stacks)
+    }
+
+    fn extract(&mut self, stacks: &[ItemStack]) -> bool {
+        self.container_mut().extract(stacks)
+    }
+
+    fn stacks(&self) -> Vec<ItemStack> {
+        self.container().stacks()
+    }
+
+    fn capacity(&self) -> usize {
+        self.container().capacity()
+    }
+
+    fn used_slots(&self) -> usize {
+        self.container().used_slots()
+    }
+
+    fn reservations(&self) -> &Reservations {
+        self.container().reservations()
+    }
+
+    fn reservations_mut(&mut self) -> &mut Reservations {
+        self.container_mut().reservations_mut()
+    }
+
+    fn change_log(&self) -> &ChangeLog {
+        self.container().change_log()
+    }
+
+    fn change_log_mut(&mut self) -> &mut ChangeLog {
+        self.container_mut().change_log_mut()
+    }
+
+    fn count(&self, item_type: &ItemType) -> ItemCount {
+        self.container().count(item_type)
+    }
+
+    fn refresh_item_types(&mut self, item_types: &ItemTypeList) {
+        self.container_mut().refresh_item_types(item_types)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn only(item_type: &ItemType) -> ItemFilter {
+        ItemFilter::Items(HashSet::from([item_type.id]))
+    }
+
+    #[test]
+    fn compact_merges_into_the_first_accepting_slot() {
+        let plate = item(1, 10);
+        let mut inventory = Inventory::new(3);
+        inventory.auto_compact = false;
+        inventory.slots = vec![Some(stack(&plate, 5)), Some(stack(&plate, 3)), Some(stack(&plate, 4))];
+        // slot 0 stopped taking plates after they were put in
+        inventory.set_slot_filter(0, ItemFilter::Items(HashSet::new()));
+        inventory.compact();
+        assert_eq!(inventory.slot(0).map(|s| s.size), Some(5));
+        assert_eq!(inventory.slot(1).map(|s| s.size), Some(7));
+        assert!(inventory.slot(2).is_none());
+        assert_eq!(inventory.count(&plate), 12);
+    }
+
+    #[test]
+    fn sort_only_uses_accepting_slots() {
+        let plate = item(1, 10);
+        let rod = item(2, 10);
+        let mut inventory = Inventory::new(3);
+        inventory.set_slot_filter(0, only(&rod));
+        inventory.slots = vec![None, Some(stack(&rod, 2)), Some(stack(&plate, 3))];
+        inventory.sort(SortMode::Id);
+        assert_eq!(inventory.slot(0).map(|s| s.item_type.id), Some(rod.id));
+        assert_eq!(inventory.slot(1).map(|s| s.item_type.id), Some(plate.id));
+        assert!(inventory.slot(2).is_none());
+    }
+
+    #[test]
+    fn sort_keeps_stacks_that_fit_nowhere_else() {
+        let plate = item(1, 10);
+        let rod = item(2, 10);
+        let mut inventory = Inventory::new(2);
+        inventory.slots = vec![Some(stack(&rod, 2)), Some(stack(&plate, 3))];
+        inventory.set_slot_filter(0, only(&plate));
+        inventory.set_slot_filter(1, only(&plate));
+        inventory.sort(SortMode::Id);
+        assert_eq!(inventory.slot(0).map(|s| s.item_type.id), Some(rod.id));
+        assert_eq!(inventory.slot(1).map(|s| s.item_type.id), Some(plate.id));
+    }
+
+    #[test]
+    fn sort_never_loses_items() {
+        let plate = item(1, 10);
+        let rod = item(2, 10);
+        let gear = item(3, 10);
+        let mut inventory = Inventory::new(4);
+        inventory.auto_compact = false;
+        inventory.slots = vec![Some(stack(&gear, 1)), Some(stack(&plate, 4)), Some(stack(&rod, 2)), Some(stack(&plate, 9))];
+        inventory.set_slot_filter(0, only(&gear));
+        inventory.set_slot_filter(3, only(&rod));
+        inventory.sort(SortMode::Count);
+        assert_eq!(inventory.count(&plate), 13);
+        assert_eq!(inventory.count(&rod), 2);
+        assert_eq!(inventory.count(&gear), 1);
+        for (i, slot) in inventory.slots.iter().enumerate() {
+            if let Some(s) = slot {
+                assert!(inventory.accepts(i, &s.item_type), "{} in slot {}", s, i);
             }
-            return true;
         }
-        false
+    }
+
+    fn sent<E: Event + Clone>(app: &App) -> Vec<E> {
+        app.world().resource::<Events<E>>().iter_current_update_events().cloned().collect()
+    }
+
+    #[test]
+    fn changes_are_sent_as_events() {
+        let plate = item(1, 10);
+        let mut app = App::new();
+        app.add_event::<ItemsInserted>().add_event::<ItemsExtracted>();
+        app.add_event::<InventoryFull>().add_event::<InventoryEmptied>();
+        app.add_systems(Update, emit_inventory_events::<Inventory>);
+        let entity = app.world_mut().spawn(Inventory::new(2)).id();
+        let update = |app: &mut App, change: &dyn Fn(&mut Inventory)| {
+            change(&mut app.world_mut().get_mut::<Inventory>(entity).unwrap());
+            app.update();
+        };
+        update(&mut app, &|inventory| { inventory.add(&[stack(&plate, 4)]); });
+        let inserted: Vec<_> = sent::<ItemsInserted>(&app).iter().map(|e| (e.entity, e.container, e.item_id, e.delta)).collect();
+        assert_eq!(inserted, vec![(entity, ContainerKind::Inventory, plate.id, 4)]);
+        assert!(sent::<InventoryFull>(&app).is_empty());
+        update(&mut app, &|inventory| { inventory.add(&[stack(&plate, 16)]); });
+        let full: Vec<_> = sent::<InventoryFull>(&app).iter().map(|e| (e.entity, e.container)).collect();
+        assert_eq!(full, vec![(entity, ContainerKind::Inventory)]);
+        update(&mut app, &|inventory| { inventory.remove(&[stack(&plate, 5)]); });
+        let extracted: Vec<_> = sent::<ItemsExtracted>(&app).iter().map(|e| (e.item_id, e.delta)).collect();
+        assert_eq!(extracted, vec![(plate.id, 5)]);
+        assert!(sent::<InventoryEmptied>(&app).is_empty());
+        update(&mut app, &|inventory| { inventory.remove(&[stack(&plate, 15)]); });
+        let emptied: Vec<_> = sent::<InventoryEmptied>(&app).iter().map(|e| (e.entity, e.container)).collect();
+        assert_eq!(emptied, vec![(entity, ContainerKind::Inventory)]);
+        // nothing changed, nothing is sent
+        app.update();
+        assert!(sent::<ItemsExtracted>(&app).is_empty());
+    }
+
+    #[test]
+    fn storages_sort_with_either_backend() {
+        let plate = item(1, 10);
+        let rod = item(2, 10);
+        for backend in [StorageBackend::Slotted, StorageBackend::Hashed] {
+            let mut storage = Storage::new(backend, 3);
+            storage.insert(&[stack(&rod, 2), stack(&plate, 3)]);
+            assert_eq!(storage.sort(SortMode::Id), vec![stack(&plate, 3), stack(&rod, 2)], "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn swap_and_split_respect_slot_filters() {
+        let plate = item(1, 10);
+        let rod = item(2, 10);
+        let mut inventory = Inventory::new(3);
+        inventory.auto_compact = false;
+        inventory.slots = vec![Some(stack(&plate, 6)), Some(stack(&rod, 2)), None];
+        inventory.set_slot_filter(1, only(&rod));
+        inventory.set_slot_filter(2, only(&rod));
+        assert!(!inventory.swap_slots(0, 1));
+        assert!(inventory.swap_slots(1, 2));
+        // the only empty slot takes rods
+        assert_eq!(inventory.split_slot(0, 3), None);
+        assert_eq!(inventory.split_slot(2, 1), Some(1));
+        assert_eq!(inventory.count(&rod), 2);
+    }
+
+    #[test]
+    fn split_needs_an_unreserved_slot() {
+        let plate = item(1, 10);
+        let mut inventory = Inventory::new(2);
+        inventory.add(&[stack(&plate, 6)]);
+        assert!(inventory.reserve_slots(Entity::from_raw(1), 1, None));
+        assert_eq!(inventory.split_slot(0, 3), None);
+        inventory.cancel_reservations(Entity::from_raw(1));
+        assert_eq!(inventory.split_slot(0, 3), Some(1));
+        assert_eq!(inventory.slot(0).map(|s| s.size), Some(3));
     }
 }
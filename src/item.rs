@@ -1,15 +1,101 @@
-use std::ops::{Sub, Add};
-use std::cmp::Ordering;
+use std::cmp::{min, Ordering};
 use std::collections::HashMap;
 use std::fmt::Display;
+use std::hash::{Hash, Hasher};
 
 use bevy::prelude::*;
 
-#[derive(serde::Deserialize, Asset, TypePath, Clone)]
+// amount of items in a stack, container or recipe
+// bulk storage can switch to 64 bit counts with the `wide_counts` feature
+#[cfg(not(feature = "wide_counts"))]
+pub type ItemCount = u32;
+#[cfg(feature = "wide_counts")]
+pub type ItemCount = u64;
+
+// add up item counts, saturating at the largest count instead of overflowing
+pub fn saturating_sum<I: IntoIterator<Item = ItemCount>>(counts: I) -> ItemCount {
+    let mut total: ItemCount = 0;
+    for count in counts {
+        match total.checked_add(count) {
+            Some(sum) => total = sum,
+            None => {
+                warn!("Item count overflowed, saturating at {}", ItemCount::MAX);
+                return ItemCount::MAX;
+            }
+        }
+    }
+    total
+}
+
+// number of slots needed to hold `amount` items, items that can't be stacked never fit
+pub fn slots_for(amount: ItemCount, max_stack: ItemCount) -> usize {
+    if max_stack == 0 {
+        return if amount == 0 { 0 } else { usize::MAX };
+    }
+    usize::try_from(amount.div_ceil(max_stack)).unwrap_or(usize::MAX)
+}
+
+#[derive(serde::Deserialize, Asset, TypePath, Clone, Debug)]
 pub struct ItemType {
     pub name: String,
+    // namespaced id from the asset file, e.g. `base:iron_plate`
+    #[serde(rename = "id")]
+    pub key: String,
+    // runtime id assigned when the item types are loaded
+    #[serde(skip)]
     pub id: u16,
-    pub max_stack: u16,
+    pub max_stack: ItemCount,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default = "default_category")]
+    pub category: String,
+}
+
+fn default_category() -> String {
+    "misc".to_string()
+}
+
+impl ItemType {
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.iter().any(|t| t == tag)
+    }
+}
+
+// changes a pack makes to an item defined by another pack, fields left out keep their value
+#[derive(serde::Deserialize, Asset, TypePath, Clone, Debug)]
+pub struct ItemPatch {
+    #[serde(rename = "id")]
+    pub key: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub max_stack: Option<ItemCount>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub add_tags: Vec<String>,
+    #[serde(default)]
+    pub remove_tags: Vec<String>,
+}
+
+impl ItemPatch {
+    pub fn apply(&self, item_type: &mut ItemType) {
+        if let Some(name) = &self.name {
+            item_type.name = name.clone();
+        }
+        if let Some(max_stack) = self.max_stack {
+            item_type.max_stack = max_stack;
+        }
+        if let Some(category) = &self.category {
+            item_type.category = category.clone();
+        }
+        item_type.tags.retain(|t| !self.remove_tags.contains(t));
+        for tag in self.add_tags.iter() {
+            if !item_type.has_tag(tag) {
+                item_type.tags.push(tag.clone());
+            }
+        }
+    }
 }
 
 impl PartialEq for ItemType {
@@ -20,13 +106,64 @@ impl PartialEq for ItemType {
 
 impl Eq for ItemType {}
 
-#[derive(Resource)]
-pub struct ItemTypeList(pub HashMap<u16, ItemType>);
+impl Hash for ItemType {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.id.hash(state);
+    }
+}
+
+// all loaded item types, indexed by id and tag
+#[derive(Resource, Default)]
+pub struct ItemTypeList {
+    types: HashMap<u16, ItemType>,
+    by_tag: HashMap<String, Vec<u16>>,
+}
+
+impl ItemTypeList {
+    // add an item type, replacing any previous type with the same id
+    pub fn insert(&mut self, item_type: ItemType) {
+        self.remove(item_type.id);
+        for tag in item_type.tags.iter() {
+            Self::index(&mut self.by_tag, tag, item_type.id);
+        }
+        self.types.insert(item_type.id, item_type);
+    }
+
+    pub fn remove(&mut self, id: u16) -> Option<ItemType> {
+        let old = self.types.remove(&id)?;
+        for ids in self.by_tag.values_mut() {
+            ids.retain(|i| *i != id);
+        }
+        self.by_tag.retain(|_, ids| !ids.is_empty());
+        Some(old)
+    }
+
+    // keep the id lists sorted so lookups are deterministic
+    fn index(index: &mut HashMap<String, Vec<u16>>, key: &str, id: u16) {
+        let ids = index.entry(key.to_string()).or_default();
+        if let Err(pos) = ids.binary_search(&id) {
+            ids.insert(pos, id);
+        }
+    }
+
+    pub fn get(&self, id: u16) -> Option<&ItemType> {
+        self.types.get(&id)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ItemType> {
+        self.types.values()
+    }
 
-#[derive(Clone)]
+    // item types with the given tag, ordered by id
+    pub fn with_tag(&self, tag: &str) -> impl Iterator<Item = &ItemType> {
+        self.by_tag.get(tag).into_iter().flatten().filter_map(|id| self.types.get(id))
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct ItemStack {
     pub item_type: ItemType,
-    pub size: u16,
+    pub size: ItemCount,
 }
 
 impl Display for ItemStack {
@@ -53,44 +190,185 @@ impl PartialOrd for ItemStack {
     }
 }
 
-impl Sub for ItemStack {
-    type Output = Self;
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StackError {
+    TypeMismatch { expected: u16, found: u16 },
+    Underflow { available: ItemCount, requested: ItemCount },
+    ExceedsMaxStack { size: ItemCount, max_stack: ItemCount },
+    Overflow,
+}
 
-    fn sub(self, rhs: Self) -> Self::Output {
-        if self.partial_cmp(&rhs).is_none() {
-            panic!("Cannot subtract item stacks with different types, or with a negative size result!");
-        }
-        Self {
-            item_type: self.item_type,
-            size: self.size - rhs.size,
+impl Display for StackError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StackError::TypeMismatch { expected, found } =>
+                write!(f, "expected item type {}, found {}", expected, found),
+            StackError::Underflow { available, requested } =>
+                write!(f, "requested {} items but only {} are available", requested, available),
+            StackError::ExceedsMaxStack { size, max_stack } =>
+                write!(f, "stack of {} exceeds the max stack size of {}", size, max_stack),
+            StackError::Overflow =>
+                write!(f, "item count overflowed"),
         }
     }
 }
 
-impl Add for ItemStack {
-    type Output = Self;
+impl std::error::Error for StackError {}
+
+impl ItemStack {
+    pub fn new(item_type: ItemType, size: ItemCount) -> Self {
+        ItemStack { item_type, size }
+    }
 
-    fn add(self, rhs: Self) -> Self::Output {
-        if self.item_type != rhs.item_type {
-            panic!("Cannot add item stacks with different types!");
+    fn check_type(&self, other: &ItemStack) -> Result<(), StackError> {
+        if self.item_type != other.item_type {
+            return Err(StackError::TypeMismatch { expected: self.item_type.id, found: other.item_type.id });
         }
-        Self {
-            item_type: self.item_type,
-            size: self.size + rhs.size,
+        Ok(())
+    }
+
+    // space left before this stack reaches its max stack size
+    pub fn room(&self) -> ItemCount {
+        self.item_type.max_stack.saturating_sub(self.size)
+    }
+
+    pub fn checked_add(&self, other: &ItemStack) -> Result<ItemStack, StackError> {
+        self.check_type(other)?;
+        let size = self.size.checked_add(other.size).ok_or(StackError::Overflow)?;
+        if size > self.item_type.max_stack {
+            return Err(StackError::ExceedsMaxStack { size, max_stack: self.item_type.max_stack });
         }
+        Ok(ItemStack { item_type: self.item_type.clone(), size })
+    }
+
+    pub fn checked_sub(&self, other: &ItemStack) -> Result<ItemStack, StackError> {
+        self.check_type(other)?;
+        let size = self.size
+            .checked_sub(other.size)
+            .ok_or(StackError::Underflow { available: self.size, requested: other.size })?;
+        Ok(ItemStack { item_type: self.item_type.clone(), size })
+    }
+
+    // take `amount` items off this stack into a new one
+    pub fn split(&mut self, amount: ItemCount) -> Result<ItemStack, StackError> {
+        let size = self.size
+            .checked_sub(amount)
+            .ok_or(StackError::Underflow { available: self.size, requested: amount })?;
+        self.size = size;
+        Ok(ItemStack { item_type: self.item_type.clone(), size: amount })
+    }
+
+    // move as much of `other` as fits into this stack, return how many items were moved
+    pub fn merge(&mut self, other: &mut ItemStack) -> Result<ItemCount, StackError> {
+        self.check_type(other)?;
+        let amount = min(self.room(), other.size);
+        self.size += amount;
+        other.size -= amount;
+        Ok(amount)
     }
 }
 
-impl ItemStack {
-    pub fn needed_full_slots(&self) -> u16 {
-        self.size / self.item_type.max_stack
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn mismatched_types_are_rejected() {
+        let plate = item(1, 10);
+        let rod = item(2, 10);
+        let mut a = stack(&plate, 5);
+        let mut b = stack(&rod, 5);
+        let mismatch = StackError::TypeMismatch { expected: plate.id, found: rod.id };
+        assert_eq!(a.checked_add(&b), Err(mismatch.clone()));
+        assert_eq!(a.checked_sub(&b), Err(mismatch.clone()));
+        assert_eq!(a.merge(&mut b), Err(mismatch));
+        assert_eq!((a.size, b.size), (5, 5));
+    }
+
+    #[test]
+    fn taking_too_much_underflows() {
+        let plate = item(1, 10);
+        let mut a = stack(&plate, 3);
+        let underflow = StackError::Underflow { available: 3, requested: 4 };
+        assert_eq!(a.checked_sub(&stack(&plate, 4)), Err(underflow.clone()));
+        assert_eq!(a.split(4), Err(underflow));
+        assert_eq!(a.size, 3);
+        assert_eq!(a.split(3), Ok(stack(&plate, 3)));
+        assert_eq!(a.size, 0);
+    }
+
+    #[test]
+    fn adding_past_the_max_stack_is_an_error() {
+        let plate = item(1, 10);
+        assert_eq!(
+            stack(&plate, 6).checked_add(&stack(&plate, 5)),
+            Err(StackError::ExceedsMaxStack { size: 11, max_stack: 10 })
+        );
+        assert_eq!(stack(&plate, 6).checked_add(&stack(&plate, 4)), Ok(stack(&plate, 10)));
+    }
+
+    #[test]
+    fn adding_past_the_largest_count_overflows() {
+        let plate = item(1, ItemCount::MAX);
+        assert_eq!(stack(&plate, ItemCount::MAX).checked_add(&stack(&plate, 1)), Err(StackError::Overflow));
+        assert_eq!(saturating_sum([ItemCount::MAX, 1]), ItemCount::MAX);
+    }
+
+    #[test]
+    fn merge_moves_only_what_fits() {
+        let plate = item(1, 10);
+        let mut a = stack(&plate, 7);
+        let mut b = stack(&plate, 5);
+        assert_eq!(a.merge(&mut b), Ok(3));
+        assert_eq!((a.size, b.size), (10, 2));
+    }
+
+    #[test]
+    fn slots_needed_for_a_stack() {
+        assert_eq!(slots_for(25, 10), 3);
+        assert_eq!(slots_for(20, 10), 2);
+        // items that can't be stacked never fit
+        assert_eq!(slots_for(1, 0), usize::MAX);
+        assert_eq!(slots_for(0, 0), 0);
+    }
+
+    fn ids(items: &ItemTypeList, tag: &str) -> Vec<u16> {
+        items.with_tag(tag).map(|i| i.id).collect()
+    }
+
+    #[test]
+    fn tagged_items_are_listed_by_id() {
+        let mut items = ItemTypeList::default();
+        for id in [3, 1, 2] {
+            items.insert(tagged_item(id, 10, &["metal"]));
+        }
+        items.insert(tagged_item(4, 10, &["fuel"]));
+        assert_eq!(ids(&items, "metal"), vec![1, 2, 3]);
+        assert_eq!(ids(&items, "fuel"), vec![4]);
+        assert!(ids(&items, "wood").is_empty());
     }
 
-    pub fn needed_partial_slots(&self) -> u16 {
-        if self.size % self.item_type.max_stack > 0 { 1 } else { 0 } 
+    #[test]
+    fn removed_items_leave_the_tag_index() {
+        let mut items = ItemTypeList::default();
+        items.insert(tagged_item(1, 10, &["metal", "fuel"]));
+        items.insert(tagged_item(2, 10, &["metal"]));
+        assert_eq!(items.remove(1).map(|i| i.id), Some(1));
+        assert_eq!(items.remove(1).map(|i| i.id), None);
+        assert!(items.get(1).is_none());
+        assert_eq!(ids(&items, "metal"), vec![2]);
+        assert!(ids(&items, "fuel").is_empty());
     }
 
-    pub fn needed_slots(&self) -> u16 {
-        self.needed_full_slots() + self.needed_partial_slots()
+    #[test]
+    fn inserting_an_existing_id_replaces_its_tags() {
+        let mut items = ItemTypeList::default();
+        items.insert(tagged_item(1, 10, &["metal"]));
+        items.insert(tagged_item(1, 20, &["fuel"]));
+        assert_eq!(items.get(1).map(|i| i.max_stack), Some(20));
+        assert_eq!(items.iter().count(), 1);
+        assert!(ids(&items, "metal").is_empty());
+        assert_eq!(ids(&items, "fuel"), vec![1]);
     }
 }
@@ -1,94 +1,255 @@
-// junk code i think? might be used in a later re-implementation for inventories
+use std::cmp::min;
+use std::collections::HashMap;
 
+use bevy::prelude::*;
+
+use crate::item::*;
+use crate::inventory::*;
+use crate::reservation::*;
+
+// hash based inventory, keeps one total per item type instead of individual slots
+// slots are only used to limit how much can be stored
+#[derive(Clone)]
 pub struct ItemSet {
-    items: HashMap<ItemType, u16>,
-    slots: u16,
+    items: HashMap<ItemType, ItemCount>,
+    slots: usize,
+    reservations: Reservations,
+    changes: ChangeLog,
 }
 
 impl Default for ItemSet {
     fn default() -> Self {
         Self {
-            items: HashMap::<ItemType, u16>::new(),
+            items: HashMap::<ItemType, ItemCount>::new(),
             slots: 1,
+            reservations: Reservations::default(),
+            changes: ChangeLog::default(),
         }
     }
 }
 
 impl ItemSet {
-    pub fn from_item_stacks(stacks: &Vec<ItemStack>) -> Self {
-        let mut new = ItemSet::default();
-        for stack in stacks.iter() {
-            if let Some(amount) = new.items.get(&stack.item_type) {
-                new.items.insert(stack.item_type.clone(), stack.size + amount);
-            } else {
-                new.items.insert(stack.item_type.clone(), stack.size);
-            }
+    pub fn new(slots: usize) -> Self {
+        Self {
+            items: HashMap::<ItemType, ItemCount>::new(),
+            slots,
+            reservations: Reservations::default(),
+            changes: ChangeLog::default(),
         }
-        new.slots = new.used_slots();
-        new
     }
 
-    pub fn from_hashmap(map: HashMap<ItemType, u16>) -> Self {
-        let mut new = ItemSet { items: map, slots: 0 };
-        new.slots = new.used_slots();
-        new
+    pub fn amount(&self, item_type: &ItemType) -> ItemCount {
+        self.items.get(item_type).copied().unwrap_or(0)
     }
 
-    pub fn used_slots(&self) -> u16 {
-        let mut used_slots: u16 = 0;
+    // contents split into full stacks, in the given order
+    pub fn sorted_stacks(&self, mode: SortMode) -> Vec<ItemStack> {
+        let mut stacks = Vec::<ItemStack>::new();
         for (item_type, amount) in self.items.iter() {
-            used_slots += amount / item_type.max_stack;
-            if amount % item_type.max_stack > 0 {
-                used_slots += 1;
+            let mut remaining = *amount;
+            while remaining > 0 {
+                let size = min(remaining, item_type.max_stack);
+                stacks.push(ItemStack { item_type: item_type.clone(), size });
+                remaining -= size;
             }
         }
-        used_slots
+        sort_stacks(&mut stacks, mode);
+        stacks
     }
 
-    pub fn free_slots(&self) -> u16 {
-        self.slots - self.used_slots()
+    pub fn used_slots(&self) -> usize {
+        let mut used_slots: usize = 0;
+        for (item_type, amount) in self.items.iter() {
+            used_slots += slots_for(*amount, item_type.max_stack);
+        }
+        used_slots
     }
 
-    // add other to self, return leftovers
-    pub fn add(&self, other: &Self) -> Option<Self> {
-        let mut free_slots = self.free_slots();
-        let mut to_insert = other.items.clone();
-        for (item_type, amount) in to_insert.iter() {
-            if free_slots == 0 {
-                break;
+    // add stacks to self, return leftovers
+    pub fn add_as(&mut self, owner: Option<Entity>, stacks: &[ItemStack]) -> Vec<ItemStack> {
+        let used_before = self.used_slots();
+        let mut free_slots = self.available_slots(owner);
+        let mut leftovers = Vec::<ItemStack>::new();
+        for stack in stacks.iter() {
+            let current = self.amount(&stack.item_type);
+            let max_stack = stack.item_type.max_stack;
+            let current_slots = slots_for(current, max_stack);
+            // room left in the partial slot of this type, plus every free slot, capped at the largest count
+            let capacity = ItemCount::try_from(current_slots + free_slots)
+                .unwrap_or(ItemCount::MAX)
+                .saturating_mul(max_stack);
+            let room = capacity.saturating_sub(current);
+            let amount = min(room, stack.size);
+            if amount > 0 {
+                self.items.insert(stack.item_type.clone(), current + amount);
+                self.changes.record(stack.item_type.id, amount as i128);
+                free_slots = free_slots.saturating_sub(slots_for(current + amount, max_stack) - current_slots);
+            }
+            if amount < stack.size {
+                leftovers.push(ItemStack { item_type: stack.item_type.clone(), size: stack.size - amount });
             }
         }
-        if to_insert.len() == 0 {
-            return None;
+        if let Some(owner) = owner {
+            let used_slots = self.used_slots().saturating_sub(used_before);
+            self.reservations.consume_slots(owner, used_slots);
         }
-        Some(Self::from_hashmap(to_insert))
+        leftovers
     }
 
-    pub fn contains(&self, other: &Self) -> bool {
-        for (item_type, amount) in other.items.iter() {
-            if let Some(self_amount) = self.items.get(item_type) {
-                if self_amount < amount {
-                    return false;
+    pub fn remove(&mut self, stacks: &[ItemStack]) -> bool {
+        if !self.contains(stacks) {
+            return false;
+        }
+        for stack in stacks.iter() {
+            let held = ItemStack::new(stack.item_type.clone(), self.amount(&stack.item_type));
+            let rest = match held.checked_sub(stack) {
+                Ok(rest) => rest,
+                Err(e) => {
+                    error!("Couldn't remove {} from an item set: {}", stack, e);
+                    continue;
                 }
+            };
+            self.changes.record(stack.item_type.id, -(stack.size as i128));
+            if rest.size > 0 {
+                self.items.insert(stack.item_type.clone(), rest.size);
             } else {
-                return false; // this set doesnt contain this item type
+                self.items.remove(&stack.item_type);
             }
         }
         true
     }
+}
 
-    pub fn remove(&mut self, other: &Self) -> bool {
-        if !self.contains(other) {
-            return false;
-        }
-        for (item_type, amount) in other.items.iter() {
-            let new_amount = self.items.get(item_type).unwrap() - amount;
-            if new_amount > 0 {
-                self.items.insert(item_type.clone(), new_amount);
-            } else {
-                self.items.remove(item_type);
-            }
-        }
-        true
+impl ItemContainer for ItemSet {
+    fn insert_as(&mut self, owner: Option<Entity>, stacks: &[ItemStack]) -> Vec<ItemStack> {
+        self.add_as(owner, stacks)
+    }
+
+    fn extract(&mut self, stacks: &[ItemStack]) -> bool {
+        self.remove(stacks)
+    }
+
+    // one stack per item type, holding the full amount
+    fn stacks(&self) -> Vec<ItemStack> {
+        self.items
+            .iter()
+            .map(|(item_type, amount)| ItemStack { item_type: item_type.clone(), size: *amount })
+            .collect()
+    }
+
+    fn capacity(&self) -> usize {
+        self.slots
+    }
+
+    fn used_slots(&self) -> usize {
+        ItemSet::used_slots(self)
+    }
+
+    fn reservations(&self) -> &Reservations {
+        &self.reservations
+    }
+
+    fn reservations_mut(&mut self) -> &mut Reservations {
+        &mut self.reservations
+    }
+
+    fn change_log(&self) -> &ChangeLog {
+        &self.changes
+    }
+
+    fn change_log_mut(&mut self) -> &mut ChangeLog {
+        &mut self.changes
+    }
+
+    fn count(&self, item_type: &ItemType) -> ItemCount {
+        self.amount(item_type)
+    }
+
+    fn refresh_item_types(&mut self, item_types: &ItemTypeList) {
+        self.items = self.items
+            .drain()
+            .map(|(item_type, amount)| (item_types.get(item_type.id).cloned().unwrap_or(item_type), amount))
+            .collect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn partial_slots_are_filled_before_free_ones() {
+        let plate = item(1, 10);
+        let rod = item(2, 10);
+        let mut set = ItemSet::new(2);
+        assert!(set.insert(&[stack(&plate, 15)]).is_empty());
+        assert_eq!((set.used_slots(), set.free_slots()), (2, 0));
+        // only the 5 that fit into the partial slot go in
+        assert_eq!(set.insert(&[stack(&plate, 8)]), vec![stack(&plate, 3)]);
+        assert_eq!(set.count(&plate), 20);
+        assert_eq!(set.insert(&[stack(&rod, 1)]), vec![stack(&rod, 1)]);
+    }
+
+    #[test]
+    fn free_slots_are_shared_between_stacks() {
+        let plate = item(1, 10);
+        let rod = item(2, 4);
+        let mut set = ItemSet::new(3);
+        // the plates take two slots, which leaves one for 4 of the rods
+        assert_eq!(set.insert(&[stack(&plate, 12), stack(&rod, 6)]), vec![stack(&rod, 2)]);
+        assert_eq!((set.used_slots(), set.free_slots()), (3, 0));
+        assert_eq!(set.count(&rod), 4);
+    }
+
+    #[test]
+    fn removing_needs_every_stack() {
+        let plate = item(1, 10);
+        let rod = item(2, 10);
+        let mut set = ItemSet::new(2);
+        set.insert(&[stack(&plate, 12)]);
+        set.change_log_mut().drain();
+        assert!(set.contains(&[stack(&plate, 12)]));
+        assert!(!set.contains(&[stack(&plate, 6), stack(&rod, 1)]));
+        assert!(!set.extract(&[stack(&plate, 6), stack(&rod, 1)]));
+        assert_eq!(set.count(&plate), 12);
+        assert!(set.extract(&[stack(&plate, 12)]));
+        assert!(set.is_empty());
+        assert_eq!(set.free_slots(), 2);
+        assert_eq!(set.change_log_mut().drain(), vec![(plate.id, -12)]);
+    }
+
+    #[test]
+    fn reservations_hold_on_a_set() {
+        let plate = item(1, 10);
+        let (owner, other) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut set = ItemSet::new(2);
+        set.insert(&[stack(&plate, 5)]);
+        assert!(set.reserve_slots(owner, 1, None));
+        // the partial slot is open to anyone, the reserved one only to its owner
+        assert_eq!(set.insert_as(Some(other), &[stack(&plate, 10)]), vec![stack(&plate, 5)]);
+        assert!(set.insert_as(Some(owner), &[stack(&plate, 5)]).is_empty());
+        assert_eq!(set.reservations().reserved_slots(None), 0);
+    }
+
+    #[test]
+    fn sorted_stacks_are_split_into_full_stacks() {
+        let plate = item(1, 10);
+        let rod = item(2, 4);
+        let mut set = ItemSet::new(4);
+        set.insert(&[stack(&rod, 6), stack(&plate, 12)]);
+        assert_eq!(set.sorted_stacks(SortMode::Id), vec![stack(&plate, 10), stack(&plate, 2), stack(&rod, 4), stack(&rod, 2)]);
+        assert_eq!(set.sorted_stacks(SortMode::Count), vec![stack(&plate, 10), stack(&rod, 4), stack(&plate, 2), stack(&rod, 2)]);
+    }
+
+    #[test]
+    fn sorted_stacks_by_name_and_tag() {
+        let zinc = ItemType { name: "Zinc".to_string(), ..tagged_item(1, 10, &["metal"]) };
+        let coal = ItemType { name: "Coal".to_string(), ..item(2, 10) };
+        let mut set = ItemSet::new(2);
+        set.insert(&[stack(&zinc, 1), stack(&coal, 1)]);
+        assert_eq!(set.sorted_stacks(SortMode::Name), vec![stack(&coal, 1), stack(&zinc, 1)]);
+        // untagged items go last
+        assert_eq!(set.sorted_stacks(SortMode::Tag), vec![stack(&zinc, 1), stack(&coal, 1)]);
     }
 }
@@ -1,22 +1,45 @@
 use std::collections::HashMap;
+use std::fmt::Display;
 use bevy::prelude::*;
 
 use crate::state::*;
 use crate::item::*;
 use crate::recipe::*;
 use crate::inventory::*;
+use crate::reservation::*;
+use crate::transaction::*;
+use crate::asset::*;
+use crate::ids::*;
+use crate::simulation::*;
 
 pub struct MachinePlugin;
 
 impl Plugin for MachinePlugin {
     fn build(&self, app: &mut App) {
+        app.add_event::<CraftStarted>()
+            .add_event::<CraftCompleted>()
+            .add_event::<SetRecipeRequest>()
+            .add_event::<RecipeChanged>()
+            .add_event::<RecipeRejected>();
+        // chained all the way, so machines always act in the same order within a tick
         app.add_systems(
-            OnEnter(AppState::InGame),
-            (spawn_machine, set_recipe, spawn_inputs).chain());
+            SimTick,
+            (
+                (expire_reservations::<InputInventory>, expire_reservations::<OutputInventory>).chain(),
+                (apply_recipe_requests, sync_input_filters, start_crafts, update_crafting_state, spawn_craft_outputs).chain(),
+                (emit_inventory_events::<InputInventory>, emit_inventory_events::<OutputInventory>).chain(),
+                wake_machines,
+            ).chain()
+        );
         app.add_systems(
             Update,
-            (start_crafts, update_crafting_state, spawn_craft_outputs)
+            (
+                refresh_machines,
+                refresh_item_types::<InputInventory>,
+                refresh_item_types::<OutputInventory>,
+            ).after(reload_definitions).run_if(in_state(AppState::InGame))
         );
+        app.add_systems(Update, log_machine_events.run_if(in_state(AppState::InGame)));
     }
 }
 
@@ -24,9 +47,84 @@ impl Plugin for MachinePlugin {
 pub struct MachineTemplate {
     pub name: String,
     pub sprite_name: String,
+    #[serde(rename = "id")]
+    pub key: String,
+    #[serde(skip)]
     pub id: u16,
     pub crafting_speed: f32,
-    pub valid_recipes: Vec<u16>,
+    // recipe categories the machine crafts every recipe of
+    #[serde(default)]
+    pub crafting_categories: Vec<String>,
+    // recipe ids from the asset file, allowed whatever their category
+    #[serde(default)]
+    pub valid_recipes: Vec<String>,
+    // runtime ids of every recipe the machine can craft, filled in when the machines are loaded
+    #[serde(skip)]
+    pub valid_recipe_ids: Vec<u16>,
+}
+
+// changes a pack makes to a machine defined by another pack, fields left out keep their value
+#[derive(serde::Deserialize, Asset, TypePath, Clone)]
+pub struct MachinePatch {
+    #[serde(rename = "id")]
+    pub key: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub sprite_name: Option<String>,
+    #[serde(default)]
+    pub crafting_speed: Option<f32>,
+    #[serde(default)]
+    pub add_categories: Vec<String>,
+    #[serde(default)]
+    pub remove_categories: Vec<String>,
+    #[serde(default)]
+    pub add_recipes: Vec<String>,
+    #[serde(default)]
+    pub remove_recipes: Vec<String>,
+}
+
+impl MachinePatch {
+    pub fn apply(&self, template: &mut MachineTemplate) {
+        if let Some(name) = &self.name {
+            template.name = name.clone();
+        }
+        if let Some(sprite_name) = &self.sprite_name {
+            template.sprite_name = sprite_name.clone();
+        }
+        if let Some(crafting_speed) = self.crafting_speed {
+            template.crafting_speed = crafting_speed;
+        }
+        template.crafting_categories.retain(|c| !self.remove_categories.contains(c));
+        for category in self.add_categories.iter() {
+            if !template.crafting_categories.contains(category) {
+                template.crafting_categories.push(category.clone());
+            }
+        }
+        template.valid_recipes.retain(|r| !self.remove_recipes.contains(r));
+        for recipe in self.add_recipes.iter() {
+            if !template.valid_recipes.contains(recipe) {
+                template.valid_recipes.push(recipe.clone());
+            }
+        }
+    }
+}
+
+impl MachineTemplate {
+    // listed recipes are always allowed, the others if the machine crafts their category
+    pub fn accepts_recipe(&self, recipe: &RecipeTemplate) -> bool {
+        self.valid_recipes.contains(&recipe.key)
+            || recipe.category.as_ref().is_some_and(|c| self.crafting_categories.contains(c))
+    }
+
+    pub fn can_craft(&self, recipe_id: u16) -> bool {
+        self.valid_recipe_ids.contains(&recipe_id)
+    }
+
+    // ticks this machine needs for one craft of the recipe
+    pub fn craft_ticks(&self, recipe: &Recipe) -> u32 {
+        ((recipe.ticks as f32 / self.crafting_speed).ceil() as u32).max(1)
+    }
 }
 
 #[derive(Resource)]
@@ -38,93 +136,347 @@ pub struct Machine(pub MachineTemplate);
 #[derive(Component, Default)]
 pub struct SetRecipe(pub Option<Recipe>);
 
-#[derive(Component, Default, PartialEq, Eq)]
+#[derive(Component, Default, Clone, Copy, PartialEq, Eq, Hash, Debug, serde::Serialize, serde::Deserialize)]
 pub enum MachineState {
     #[default]
     Idle,
     Crafting,
     Complete,
     InputShortage,
+    // the last craft is done but its outputs don't fit yet
     OutputFull,
+    // the outputs of the next craft wouldn't fit, so its inputs aren't taken yet
+    OutputBlocked,
 }
 
-#[derive(Component, Default)]
-pub struct CraftingTimer(Timer);
+// progress of the current craft in ticks
+#[derive(Component, Default, Clone, Copy, PartialEq, Eq, Hash, Debug, serde::Serialize, serde::Deserialize)]
+pub struct CraftingTimer {
+    pub elapsed: u32,
+    pub duration: u32,
+}
 
-#[derive(Component, Default)]
+impl CraftingTimer {
+    pub fn start(duration: u32) -> Self {
+        CraftingTimer { elapsed: 0, duration }
+    }
+
+    pub fn tick(&mut self) {
+        self.elapsed = self.elapsed.saturating_add(1).min(self.duration);
+    }
+
+    pub fn finished(&self) -> bool {
+        self.elapsed >= self.duration
+    }
+}
+
+// what the current craft took from the input inventory, given back if the craft can't finish
+#[derive(Component, Default, Clone, Debug)]
+pub struct CraftInputs(pub Vec<ItemStack>);
+
+// a machine took the inputs for a craft
+#[derive(Event, Debug)]
+pub struct CraftStarted {
+    pub entity: Entity,
+    pub recipe_id: u16,
+    pub inputs: Vec<ItemStack>,
+}
+
+// a machine put the outputs of a craft into its output inventory
+#[derive(Event, Debug)]
+pub struct CraftCompleted {
+    pub entity: Entity,
+    pub recipe_id: u16,
+    pub outputs: Vec<ItemStack>,
+}
+
+// asks a machine to switch to another recipe, `None` clears it
+// applied at the start of the next tick and answered with RecipeChanged or RecipeRejected
+#[derive(Event, Clone, Debug)]
+pub struct SetRecipeRequest {
+    pub entity: Entity,
+    pub recipe_id: Option<u16>,
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct RecipeChanged {
+    pub entity: Entity,
+    pub old: Option<u16>,
+    pub new: Option<u16>,
+    // inputs of the craft that was interrupted
+    pub refunded: Vec<ItemStack>,
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct RecipeRejected {
+    pub entity: Entity,
+    pub recipe_id: Option<u16>,
+    pub reason: RecipeRejection,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecipeRejection {
+    NotAMachine,
+    UnknownRecipe,
+    // not in the machine's valid recipes
+    NotCraftable,
+    // the refund and the inputs the new recipe can't use don't fit into the inventories
+    NoSpace,
+}
+
+impl Display for RecipeRejection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RecipeRejection::NotAMachine => write!(f, "it is not a machine"),
+            RecipeRejection::UnknownRecipe => write!(f, "the recipe doesn't exist"),
+            RecipeRejection::NotCraftable => write!(f, "the machine can't craft it"),
+            RecipeRejection::NoSpace => write!(f, "there is no space for the items it would move"),
+        }
+    }
+}
+
+// the machine inventories are plain inventories, the wrappers only tell input and output apart
+macro_rules! delegate_item_container {
+    ($wrapper:ty) => {
+        impl ItemContainer for $wrapper {
+            fn insert_as(&mut self, owner: Option<Entity>, stacks: &[ItemStack]) -> Vec<ItemStack> {
+                self.0.insert_as(owner, stacks)
+            }
+
+            fn extract(&mut self, stacks: &[ItemStack]) -> bool {
+                self.0.extract(stacks)
+            }
+
+            fn stacks(&self) -> Vec<ItemStack> {
+                ItemContainer::stacks(&self.0)
+            }
+
+            fn capacity(&self) -> usize {
+                self.0.capacity()
+            }
+
+            fn used_slots(&self) -> usize {
+                ItemContainer::used_slots(&self.0)
+            }
+
+            fn reservations(&self) -> &Reservations {
+                self.0.reservations()
+            }
+
+            fn reservations_mut(&mut self) -> &mut Reservations {
+                self.0.reservations_mut()
+            }
+
+            fn change_log(&self) -> &ChangeLog {
+                self.0.change_log()
+            }
+
+            fn change_log_mut(&mut self) -> &mut ChangeLog {
+                self.0.change_log_mut()
+            }
+
+            fn count(&self, item_type: &ItemType) -> ItemCount {
+                ItemContainer::count(&self.0, item_type)
+            }
+
+            fn refresh_item_types(&mut self, item_types: &ItemTypeList) {
+                self.0.refresh_item_types(item_types)
+            }
+        }
+    };
+}
+
+#[derive(Component, Clone, Default)]
 pub struct InputInventory(pub Inventory);
 
-#[derive(Component, Default)]
+delegate_item_container!(InputInventory);
+
+impl InventoryComponent for InputInventory {
+    const KIND: ContainerKind = ContainerKind::Input;
+}
+
+#[derive(Component, Clone, Default)]
 pub struct OutputInventory(pub Inventory);
 
+delegate_item_container!(OutputInventory);
+
+impl InventoryComponent for OutputInventory {
+    const KIND: ContainerKind = ContainerKind::Output;
+}
+
 #[derive(Bundle, Default)]
 pub struct MachineBundle {
-    input: InputInventory,
-    output: OutputInventory,
-    recipe: SetRecipe,
-    state: MachineState,
-    crafting_timer: CraftingTimer,
+    pub input: InputInventory,
+    pub output: OutputInventory,
+    pub recipe: SetRecipe,
+    pub state: MachineState,
+    pub crafting_timer: CraftingTimer,
+    pub craft_inputs: CraftInputs,
 }
 
-fn spawn_machine(
-    mut commands: Commands,
-    machine_list: Res<MachineList>,
-    asset_server: Res<AssetServer>,
-) {
-    let template = machine_list.0.get(&1).unwrap();
-    println!("Spawning machine with sprite {}", template.sprite_name.clone());
-    commands.spawn((
-        Machine(template.clone()),
-        MachineBundle::default(),
-        SpriteBundle {
-            texture: asset_server.load(template.sprite_name.clone()),
-            ..default()
-        },
-    ));
-    println!("Spawned machine!");
-}
-
-fn set_recipe(
-    mut q: Query<&mut SetRecipe, With<Machine>>,
+type CraftQuery<'a> = (
+    Entity,
+    &'a Machine,
+    &'a SetRecipe,
+    &'a mut MachineState,
+    &'a mut CraftingTimer,
+    &'a mut CraftInputs,
+    &'a mut InputInventory,
+    &'a mut OutputInventory,
+);
+
+type RecipeChangeQuery<'a> = (
+    &'a Machine,
+    &'a mut SetRecipe,
+    &'a mut MachineState,
+    &'a mut CraftingTimer,
+    &'a mut CraftInputs,
+    &'a mut InputInventory,
+    &'a mut OutputInventory,
+);
+
+// a craft that is interrupted gives its inputs back, and input items the new recipe can't use go to the output
+// either all of that fits or the recipe stays as it is
+fn apply_recipe_requests(
+    mut requests: EventReader<SetRecipeRequest>,
+    mut q: Query<RecipeChangeQuery>,
     recipe_list: Res<RecipeList>,
+    mut changed: EventWriter<RecipeChanged>,
+    mut rejected: EventWriter<RecipeRejected>,
 ) {
-    for mut recipe in q.iter_mut() {
-        recipe.0 = Some(recipe_list.0.get(&1).unwrap().clone());
-        println!("Set recipe to {}", recipe.0.as_ref().unwrap().name);
+    for request in requests.read() {
+        let reject = |reason| RecipeRejected { entity: request.entity, recipe_id: request.recipe_id, reason };
+        let Ok((machine, mut recipe_opt, mut state, mut timer, mut craft_inputs, mut input, mut output)) = q.get_mut(request.entity) else {
+            rejected.send(reject(RecipeRejection::NotAMachine));
+            continue;
+        };
+        let recipe = match request.recipe_id {
+            Some(id) => match recipe_list.0.get(&id) {
+                Some(recipe) if machine.0.can_craft(id) => Some(recipe.clone()),
+                Some(_) => {
+                    warn!("Machine {} can't craft recipe {}", machine.0.key, id);
+                    rejected.send(reject(RecipeRejection::NotCraftable));
+                    continue;
+                }
+                None => {
+                    rejected.send(reject(RecipeRejection::UnknownRecipe));
+                    continue;
+                }
+            },
+            None => None,
+        };
+        let old = recipe_opt.0.as_ref().map(|r| r.id);
+        if old == request.recipe_id {
+            changed.send(RecipeChanged { entity: request.entity, old, new: old, refunded: Vec::new() });
+            continue;
+        }
+        let filter = match &recipe {
+            Some(recipe) => ItemFilter::from_recipe_inputs(recipe),
+            None => ItemFilter::Any,
+        };
+        let incompatible: Vec<ItemStack> = input.0.stacks().filter(|s| !filter.accepts(&s.item_type)).cloned().collect();
+        let (kept, mut moved): (Vec<ItemStack>, Vec<ItemStack>) =
+            craft_inputs.0.iter().cloned().partition(|s| filter.accepts(&s.item_type));
+        moved.extend(incompatible.iter().cloned());
+        // the moves are made under the new recipe's filter, and the filter only changes if they all succeed
+        let mut new_input = input.clone();
+        new_input.0.set_filter(filter);
+        // the interrupted craft doesn't need its output space anymore
+        let mut new_output = output.clone();
+        new_output.cancel_reservations(request.entity);
+        let mut transaction = Transaction::begin();
+        let input_key = transaction.enlist(&mut new_input);
+        let output_key = transaction.enlist(&mut new_output);
+        transaction
+            .remove(input_key, &incompatible)
+            .add(input_key, &kept)
+            .add(output_key, &moved);
+        if let Err(e) = transaction.commit() {
+            warn!("Machine {} can't change recipes: {}", machine.0.key, e);
+            rejected.send(reject(RecipeRejection::NoSpace));
+            continue;
+        }
+        *input = new_input;
+        *output = new_output;
+        // a finished craft whose outputs didn't fit yet is refunded too
+        *state = MachineState::Idle;
+        *timer = CraftingTimer::default();
+        let refunded = std::mem::take(&mut craft_inputs.0);
+        recipe_opt.0 = recipe;
+        println!("Machine {} now crafts {}", machine.0.key, recipe_opt.0.as_ref().map_or("nothing", |r| r.name.as_str()));
+        changed.send(RecipeChanged { entity: request.entity, old, new: request.recipe_id, refunded });
     }
 }
 
-fn spawn_inputs(
-    mut q: Query<&mut InputInventory, With<Machine>>,
-    item_types: Res<ItemTypeList>,
+// only let a machine's input accept the items its current recipe consumes
+fn sync_input_filters(
+    mut q: Query<(&SetRecipe, &mut InputInventory), Changed<SetRecipe>>,
 ) {
-    for mut inv in q.iter_mut() {
-        let _ = inv.0.add(&[
-            ItemStack { item_type: item_types.0.get(&1).unwrap().clone(), size: 10 }
-        ]);
-        println!("Spawned input stack!");
+    for (recipe_opt, mut inv) in q.iter_mut() {
+        let filter = match &recipe_opt.0 {
+            Some(recipe) => ItemFilter::from_recipe_inputs(recipe),
+            None => ItemFilter::Any,
+        };
+        inv.0.set_filter(filter);
+    }
+}
+
+// keep the empty output slots a craft's outputs will need, so a finished craft always has somewhere to go
+pub fn reserve_output(output: &mut OutputInventory, machine: Entity, outputs: &[ItemStack]) -> bool {
+    match output.slots_needed(Some(machine), outputs) {
+        Some(0) => true,
+        Some(slots) => output.reserve_slots(machine, slots, None),
+        None => false,
     }
 }
 
 fn start_crafts(
-    mut q: Query<(&Machine, &SetRecipe, &mut MachineState, &mut CraftingTimer, &mut InputInventory)>,
+    mut q: Query<CraftQuery>,
+    mut started: EventWriter<CraftStarted>,
 ) {
-    for (machine, recipe_opt, mut state, mut timer, mut inv) in q.iter_mut() {
+    for (entity, machine, recipe_opt, mut state, mut timer, mut craft_inputs, mut inv, mut output) in q.iter_mut() {
+        // the recipe was reloaded away and clearing it is still pending or was rejected
+        if recipe_opt.0.as_ref().is_some_and(|r| !machine.0.can_craft(r.id)) {
+            continue;
+        }
         if let Some(recipe) = &recipe_opt.0 {
             match *state { 
-                MachineState::Complete => println!("Machine already has completed outputs, not starting recipe"),
+                MachineState::Complete => (),
                 MachineState::Crafting => (), //println!("Machine is already crafting!"),
-                MachineState::InputShortage => println!("Machine has no inputs!"),
-                MachineState::OutputFull => println!("Machine's output is full!"),
+                // woken up by wake_machines once the inventory changes
+                MachineState::InputShortage => (),
+                MachineState::OutputFull => (),
+                MachineState::OutputBlocked => (),
                 MachineState::Idle => {
-                    println!("Input contains {}", inv.0.stacks[0]);
-                    if inv.0.remove(&recipe.inputs) {
-                        timer.0 = Timer::from_seconds(recipe.duration / machine.0.crafting_speed, TimerMode::Once);
-                        *state = MachineState::Crafting;
-                        println!("Started crafting {}!", recipe.name);
-                    } else {
+                    println!("Input contains {}", inv.0);
+                    let Some(inputs) = recipe.resolve_inputs(&*inv) else {
                         *state = MachineState::InputShortage;
                         println!("Couldn't get items for {}", recipe.name);
+                        continue;
+                    };
+                    let mut transaction = Transaction::begin();
+                    let input = transaction.enlist(&mut *inv);
+                    transaction.remove(input, &inputs);
+                    // the inputs are only taken once the outputs are sure to have room
+                    if !reserve_output(&mut output, entity, &recipe.outputs) {
+                        transaction.rollback();
+                        *state = MachineState::OutputBlocked;
+                        println!("No room for the outputs of {}", recipe.name);
+                        continue;
+                    }
+                    match transaction.commit() {
+                        Ok(()) => {
+                            *timer = CraftingTimer::start(machine.0.craft_ticks(recipe));
+                            *state = MachineState::Crafting;
+                            craft_inputs.0 = inputs.clone();
+                            println!("Started crafting {}!", recipe.name);
+                            started.send(CraftStarted { entity, recipe_id: recipe.id, inputs });
+                        }
+                        Err(e) => {
+                            output.cancel_reservations(entity);
+                            *state = MachineState::InputShortage;
+                            println!("Couldn't get items for {}: {}", recipe.name, e);
+                        }
                     }
                 }
             }
@@ -133,13 +485,12 @@ fn start_crafts(
 }
 
 fn update_crafting_state(
-    time: Res<Time>,
     mut q: Query<(&SetRecipe, &mut MachineState, &mut CraftingTimer), With<Machine>>
 ) {
     for (recipe, mut state, mut timer) in q.iter_mut() {
         if recipe.0.is_some() && *state == MachineState::Crafting {
-            timer.0.tick(time.delta());
-            if timer.0.finished() {
+            timer.tick();
+            if timer.finished() {
                 *state = MachineState::Complete;
                 println!("Finished crafting {}!", recipe.0.as_ref().unwrap().name);
             }
@@ -148,20 +499,433 @@ fn update_crafting_state(
 }
 
 fn spawn_craft_outputs(
-    mut q: Query<(&SetRecipe, &mut MachineState, &mut OutputInventory), With<Machine>>
+    mut q: Query<(Entity, &SetRecipe, &mut MachineState, &mut CraftInputs, &mut OutputInventory), With<Machine>>,
+    mut completed: EventWriter<CraftCompleted>,
 ) {
-    for (recipe_opt, mut state, mut inv) in q.iter_mut() {
+    for (entity, recipe_opt, mut state, mut craft_inputs, mut inv) in q.iter_mut() {
         if *state == MachineState::Complete {
             if let Some(recipe) = &recipe_opt.0 {
-                if inv.0.add_strict(&recipe.outputs) {
-                    *state = MachineState::Idle;
-                    println!("Spawned results of recipe {}!", recipe.name);
-                    println!("Output now contains {}", inv.0.stacks[0]);
-                } else {
-                    *state = MachineState::OutputFull;
-                    println!("Can't spawn recipe outputs, output is full!");
+                let mut transaction = Transaction::begin();
+                let output = transaction.enlist(&mut *inv);
+                transaction.add_as(output, Some(entity), &recipe.outputs);
+                match transaction.commit() {
+                    Ok(()) => {
+                        inv.cancel_reservations(entity);
+                        *state = MachineState::Idle;
+                        craft_inputs.0.clear();
+                        println!("Spawned results of recipe {}!", recipe.name);
+                        completed.send(CraftCompleted { entity, recipe_id: recipe.id, outputs: recipe.outputs.clone() });
+                        println!("Output now contains {}", inv.0);
+                    }
+                    Err(e) => {
+                        *state = MachineState::OutputFull;
+                        println!("Can't spawn recipe outputs: {}", e);
+                    }
                 }
             }
         }
     }
 }
+
+// retry machines that were waiting on their inventories
+fn wake_machines(
+    mut inserted: EventReader<ItemsInserted>,
+    mut extracted: EventReader<ItemsExtracted>,
+    mut q: Query<&mut MachineState, With<Machine>>,
+) {
+    for event in inserted.read() {
+        if event.container != ContainerKind::Input {
+            continue;
+        }
+        if let Ok(mut state) = q.get_mut(event.entity) {
+            if *state == MachineState::InputShortage {
+                *state = MachineState::Idle;
+            }
+        }
+    }
+    for event in extracted.read() {
+        if event.container != ContainerKind::Output {
+            continue;
+        }
+        if let Ok(mut state) = q.get_mut(event.entity) {
+            match *state {
+                MachineState::OutputFull => *state = MachineState::Complete,
+                MachineState::OutputBlocked => *state = MachineState::Idle,
+                _ => (),
+            }
+        }
+    }
+}
+
+// apply_recipe_requests already prints the new recipe and crafts happen every few ticks, so this is debug output
+// only rejected requests are warnings, nothing else reports them
+fn log_machine_events(
+    mut started: EventReader<CraftStarted>,
+    mut completed: EventReader<CraftCompleted>,
+    mut changed: EventReader<RecipeChanged>,
+    mut rejected: EventReader<RecipeRejected>,
+    registry: Res<IdRegistry>,
+) {
+    let recipe = |id: Option<u16>| id.map_or("no recipe", |id| registry.recipes.key(id).unwrap_or("<unknown recipe>"));
+    let items = |stacks: &[ItemStack]| stacks.iter().map(|s| s.to_string()).collect::<Vec<_>>().join(", ");
+    for event in started.read() {
+        debug!("{:?} started {} with [{}]", event.entity, recipe(Some(event.recipe_id)), items(&event.inputs));
+    }
+    for event in completed.read() {
+        debug!("{:?} completed {} into [{}]", event.entity, recipe(Some(event.recipe_id)), items(&event.outputs));
+    }
+    for event in changed.read() {
+        debug!("{:?} switched from {} to {}, refunded [{}]", event.entity, recipe(event.old), recipe(event.new), items(&event.refunded));
+    }
+    for event in rejected.read() {
+        warn!("Machine {:?} can't switch to {}: {}", event.entity, recipe(event.recipe_id), event.reason);
+    }
+}
+
+// point live machines at the reloaded definitions, keeping their state and timers
+fn refresh_machines(
+    mut reloaded: EventReader<DefinitionsReloaded>,
+    machine_list: Res<MachineList>,
+    recipe_list: Res<RecipeList>,
+    mut q: Query<(Entity, &mut Machine, &mut SetRecipe, &mut InputInventory, &mut OutputInventory)>,
+    mut requests: EventWriter<SetRecipeRequest>,
+) {
+    if reloaded.is_empty() {
+        return;
+    }
+    reloaded.clear();
+    for (entity, mut machine, mut recipe_opt, mut input, mut output) in q.iter_mut() {
+        match machine_list.0.get(&machine.0.id) {
+            Some(template) => machine.0 = template.clone(),
+            None => warn!("Machine {} is no longer defined, keeping the old definition", machine.0.key),
+        }
+        let Some(current) = &recipe_opt.0 else {
+            continue;
+        };
+        match recipe_list.0.get(&current.id) {
+            // also marks the recipe as changed, so the input filter is updated
+            // a craft in progress finishes with the inputs it already took
+            Some(recipe) if machine.0.can_craft(recipe.id) => {
+                // items the reloaded recipe doesn't use anymore would be stuck behind the new filter
+                let filter = ItemFilter::from_recipe_inputs(recipe);
+                let unused: Vec<ItemStack> = input.0.stacks().filter(|s| !filter.accepts(&s.item_type)).cloned().collect();
+                if !unused.is_empty() {
+                    let mut transaction = Transaction::begin();
+                    let input_key = transaction.enlist(&mut *input);
+                    let output_key = transaction.enlist(&mut *output);
+                    transaction.remove(input_key, &unused).add(output_key, &unused);
+                    if let Err(e) = transaction.commit() {
+                        warn!("Machine {} keeps items {} doesn't use anymore in its input: {}", machine.0.key, recipe.key, e);
+                    }
+                }
+                recipe_opt.0 = Some(recipe.clone());
+            }
+            // cleared like any other recipe change, which refunds the current craft
+            Some(_) | None => {
+                warn!("Machine {} can't craft {} anymore, clearing its recipe", machine.0.key, current.key);
+                requests.send(SetRecipeRequest { entity, recipe_id: None });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::testing::*;
+
+    struct Content {
+        ore: ItemType,
+        plate: ItemType,
+        coal: ItemType,
+        smelt: Recipe,
+        burn: Recipe,
+        machine: MachineTemplate,
+    }
+
+    fn content() -> Content {
+        let (ore, plate, coal) = (item(1, 10), item(2, 10), item(3, 10));
+        let smelt = recipe(1, 10, &[stack(&ore, 2)], &[], &[stack(&plate, 1)]);
+        let burn = recipe(2, 10, &[stack(&coal, 1)], &[], &[]);
+        let machine = machine_template(1, &[&smelt, &burn]);
+        Content { ore, plate, coal, smelt, burn, machine }
+    }
+
+    // a machine halfway through smelting, with more ore waiting in its input
+    fn world_with_machine(content: &Content) -> (World, Entity) {
+        let mut world = World::new();
+        world.init_resource::<Events<SetRecipeRequest>>();
+        world.init_resource::<Events<RecipeChanged>>();
+        world.init_resource::<Events<RecipeRejected>>();
+        world.init_resource::<Events<DefinitionsReloaded>>();
+        world.init_resource::<Events<CraftStarted>>();
+        world.init_resource::<Events<CraftCompleted>>();
+        world.init_resource::<Events<ItemsInserted>>();
+        world.init_resource::<Events<ItemsExtracted>>();
+        let recipes = [&content.smelt, &content.burn, &recipe(3, 10, &[], &[], &[])];
+        world.insert_resource(RecipeList(recipes.iter().map(|r| (r.id, (*r).clone())).collect()));
+        world.insert_resource(MachineList(HashMap::from([(content.machine.id, content.machine.clone())])));
+        let entity = world.spawn(smelter(content)).id();
+        (world, entity)
+    }
+
+    fn smelter(content: &Content) -> (Machine, MachineBundle) {
+        let mut input = Inventory::new(2);
+        input.set_filter(ItemFilter::from_recipe_inputs(&content.smelt));
+        input.add(&[stack(&content.ore, 3)]);
+        (
+            Machine(content.machine.clone()),
+            MachineBundle {
+                input: InputInventory(input),
+                output: OutputInventory(Inventory::new(1)),
+                recipe: SetRecipe(Some(content.smelt.clone())),
+                state: MachineState::Crafting,
+                crafting_timer: CraftingTimer { elapsed: 4, duration: 10 },
+                craft_inputs: CraftInputs(vec![stack(&content.ore, 2)]),
+            },
+        )
+    }
+
+    fn request(world: &mut World, entity: Entity, recipe_id: Option<u16>) {
+        world.send_event(SetRecipeRequest { entity, recipe_id });
+        world.run_system_once(apply_recipe_requests);
+    }
+
+    fn rejection(world: &World) -> Option<RecipeRejection> {
+        world.resource::<Events<RecipeRejected>>().iter_current_update_events().last().map(|r| r.reason)
+    }
+
+    fn changes(world: &World) -> Vec<RecipeChanged> {
+        world.resource::<Events<RecipeChanged>>().iter_current_update_events().cloned().collect()
+    }
+
+    fn recipe_of(world: &World, entity: Entity) -> Option<u16> {
+        world.get::<SetRecipe>(entity).unwrap().0.as_ref().map(|r| r.id)
+    }
+
+    #[test]
+    fn invalid_requests_are_rejected() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        let other = world.spawn_empty().id();
+        request(&mut world, other, None);
+        assert_eq!(rejection(&world), Some(RecipeRejection::NotAMachine));
+        request(&mut world, entity, Some(4));
+        assert_eq!(rejection(&world), Some(RecipeRejection::UnknownRecipe));
+        request(&mut world, entity, Some(3));
+        assert_eq!(rejection(&world), Some(RecipeRejection::NotCraftable));
+        assert!(changes(&world).is_empty());
+        assert_eq!(recipe_of(&world, entity), Some(content.smelt.id));
+    }
+
+    #[test]
+    fn switching_refunds_the_craft_and_moves_unusable_inputs_out() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        request(&mut world, entity, Some(content.burn.id));
+        let changes = changes(&world);
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].old, changes[0].new), (Some(content.smelt.id), Some(content.burn.id)));
+        assert_eq!(changes[0].refunded, vec![stack(&content.ore, 2)]);
+        // the new recipe doesn't use ore, so the refund and the waiting ore go to the output
+        let input = &world.get::<InputInventory>(entity).unwrap().0;
+        assert!(input.is_empty());
+        assert!(input.filter.accepts(&content.coal));
+        assert!(!input.filter.accepts(&content.ore));
+        assert_eq!(world.get::<OutputInventory>(entity).unwrap().0.count(&content.ore), 5);
+        assert_eq!(*world.get::<MachineState>(entity).unwrap(), MachineState::Idle);
+        assert_eq!(*world.get::<CraftingTimer>(entity).unwrap(), CraftingTimer::default());
+        assert!(world.get::<CraftInputs>(entity).unwrap().0.is_empty());
+    }
+
+    #[test]
+    fn no_space_leaves_the_machine_untouched() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        world.get_mut::<OutputInventory>(entity).unwrap().0.add(&[stack(&content.plate, 10)]);
+        request(&mut world, entity, Some(content.burn.id));
+        assert_eq!(rejection(&world), Some(RecipeRejection::NoSpace));
+        assert_eq!(recipe_of(&world, entity), Some(content.smelt.id));
+        let input = &world.get::<InputInventory>(entity).unwrap().0;
+        assert_eq!(input.count(&content.ore), 3);
+        assert!(input.filter.accepts(&content.ore));
+        assert!(!input.filter.accepts(&content.coal));
+        assert_eq!(world.get::<OutputInventory>(entity).unwrap().0.stacks().cloned().collect::<Vec<_>>(), vec![stack(&content.plate, 10)]);
+        assert_eq!(*world.get::<MachineState>(entity).unwrap(), MachineState::Crafting);
+        assert_eq!(world.get::<CraftingTimer>(entity).unwrap().elapsed, 4);
+        assert_eq!(world.get::<CraftInputs>(entity).unwrap().0, vec![stack(&content.ore, 2)]);
+    }
+
+    #[test]
+    fn reloading_away_the_current_recipe_clears_it_and_refunds() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        // the reloaded machine no longer crafts smelting
+        let reloaded = machine_template(1, &[&content.burn]);
+        world.resource_mut::<MachineList>().0.insert(reloaded.id, reloaded);
+        world.send_event(DefinitionsReloaded);
+        world.run_system_once(refresh_machines);
+        world.run_system_once(apply_recipe_requests);
+        let changes = changes(&world);
+        assert_eq!(changes.len(), 1);
+        assert_eq!((changes[0].old, changes[0].new), (Some(content.smelt.id), None));
+        assert_eq!(changes[0].refunded, vec![stack(&content.ore, 2)]);
+        assert_eq!(world.get::<InputInventory>(entity).unwrap().0.count(&content.ore), 5);
+        assert!(!world.get::<Machine>(entity).unwrap().0.can_craft(content.smelt.id));
+    }
+
+    #[test]
+    fn reloading_keeps_recipes_the_machine_still_crafts() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        world.send_event(DefinitionsReloaded);
+        world.run_system_once(refresh_machines);
+        assert!(world.resource::<Events<SetRecipeRequest>>().iter_current_update_events().next().is_none());
+        assert_eq!(recipe_of(&world, entity), Some(content.smelt.id));
+        assert_eq!(*world.get::<MachineState>(entity).unwrap(), MachineState::Crafting);
+    }
+
+    #[test]
+    fn crafts_take_all_their_inputs_or_none() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        world.get_mut::<CraftInputs>(entity).unwrap().0.clear();
+        *world.get_mut::<MachineState>(entity).unwrap() = MachineState::Idle;
+        world.run_system_once(start_crafts);
+        assert_eq!(*world.get::<MachineState>(entity).unwrap(), MachineState::Crafting);
+        assert_eq!(world.get::<CraftingTimer>(entity).unwrap().duration, content.smelt.ticks);
+        assert_eq!(world.get::<CraftInputs>(entity).unwrap().0, vec![stack(&content.ore, 2)]);
+        assert_eq!(world.get::<InputInventory>(entity).unwrap().0.count(&content.ore), 1);
+        // one ore isn't enough for the next craft, and none of it is taken
+        *world.get_mut::<MachineState>(entity).unwrap() = MachineState::Idle;
+        world.run_system_once(start_crafts);
+        assert_eq!(*world.get::<MachineState>(entity).unwrap(), MachineState::InputShortage);
+        assert_eq!(world.get::<InputInventory>(entity).unwrap().0.count(&content.ore), 1);
+    }
+
+    #[test]
+    fn outputs_that_dont_all_fit_stay_in_the_machine() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        // two output stacks for a single slot
+        let split = recipe(4, 10, &[stack(&content.ore, 2)], &[], &[stack(&content.plate, 5), stack(&content.coal, 5)]);
+        world.get_mut::<SetRecipe>(entity).unwrap().0 = Some(split);
+        *world.get_mut::<MachineState>(entity).unwrap() = MachineState::Complete;
+        world.run_system_once(spawn_craft_outputs);
+        assert_eq!(*world.get::<MachineState>(entity).unwrap(), MachineState::OutputFull);
+        assert!(world.get::<OutputInventory>(entity).unwrap().0.is_empty());
+        assert_eq!(world.get::<CraftInputs>(entity).unwrap().0, vec![stack(&content.ore, 2)]);
+        assert!(world.resource::<Events<CraftCompleted>>().is_empty());
+    }
+
+    #[test]
+    fn crafts_keep_room_for_their_outputs() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        world.get_mut::<CraftInputs>(entity).unwrap().0.clear();
+        *world.get_mut::<MachineState>(entity).unwrap() = MachineState::Idle;
+        world.run_system_once(start_crafts);
+        assert_eq!(*world.get::<MachineState>(entity).unwrap(), MachineState::Crafting);
+        // the only output slot is held for the plate
+        let mut output = world.get_mut::<OutputInventory>(entity).unwrap();
+        assert_eq!(output.available_slots(None), 0);
+        assert!(!output.0.add(&[stack(&content.coal, 1)]).is_empty());
+        *world.get_mut::<MachineState>(entity).unwrap() = MachineState::Complete;
+        world.run_system_once(spawn_craft_outputs);
+        let output = world.get::<OutputInventory>(entity).unwrap();
+        assert_eq!(output.0.count(&content.plate), 1);
+        assert_eq!(output.reservations().reserved_slots(None), 0);
+    }
+
+    #[test]
+    fn crafts_wait_for_room_in_the_output() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        world.get_mut::<CraftInputs>(entity).unwrap().0.clear();
+        world.get_mut::<OutputInventory>(entity).unwrap().0.add(&[stack(&content.coal, 1)]);
+        *world.get_mut::<MachineState>(entity).unwrap() = MachineState::Idle;
+        world.run_system_once(start_crafts);
+        assert_eq!(*world.get::<MachineState>(entity).unwrap(), MachineState::OutputBlocked);
+        assert_eq!(world.get::<InputInventory>(entity).unwrap().0.count(&content.ore), 3);
+        assert!(world.get::<CraftInputs>(entity).unwrap().0.is_empty());
+        // emptying the output lets the machine try again
+        assert!(world.get_mut::<OutputInventory>(entity).unwrap().0.remove(&[stack(&content.coal, 1)]));
+        world.send_event(ItemsExtracted { entity, container: ContainerKind::Output, item_id: content.coal.id, delta: 1 });
+        world.run_system_once(wake_machines);
+        assert_eq!(*world.get::<MachineState>(entity).unwrap(), MachineState::Idle);
+        world.run_system_once(start_crafts);
+        assert_eq!(*world.get::<MachineState>(entity).unwrap(), MachineState::Crafting);
+    }
+
+    #[test]
+    fn switching_recipes_gives_back_the_reserved_output() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        assert!(world.get_mut::<OutputInventory>(entity).unwrap().reserve_slots(entity, 1, None));
+        request(&mut world, entity, None);
+        assert_eq!(world.get::<OutputInventory>(entity).unwrap().reservations().reserved_slots(None), 0);
+    }
+
+    #[test]
+    fn inventory_events_wake_waiting_machines() {
+        let content = content();
+        let mut app = App::new();
+        app.add_event::<ItemsInserted>().add_event::<ItemsExtracted>();
+        app.add_event::<InventoryFull>().add_event::<InventoryEmptied>();
+        app.add_systems(
+            Update,
+            ((emit_inventory_events::<InputInventory>, emit_inventory_events::<OutputInventory>).chain(), wake_machines).chain(),
+        );
+        let entity = app.world_mut().spawn(smelter(&content)).id();
+        // the ore the input starts with isn't news
+        app.world_mut().get_mut::<InputInventory>(entity).unwrap().change_log_mut().drain();
+        app.world_mut().get_mut::<OutputInventory>(entity).unwrap().0.add(&[stack(&content.coal, 1)]);
+        *app.world_mut().get_mut::<MachineState>(entity).unwrap() = MachineState::InputShortage;
+        app.update();
+        // more in the output doesn't help a machine short on inputs
+        assert_eq!(*app.world().get::<MachineState>(entity).unwrap(), MachineState::InputShortage);
+        app.world_mut().get_mut::<InputInventory>(entity).unwrap().0.add(&[stack(&content.ore, 1)]);
+        app.update();
+        assert_eq!(*app.world().get::<MachineState>(entity).unwrap(), MachineState::Idle);
+        *app.world_mut().get_mut::<MachineState>(entity).unwrap() = MachineState::OutputBlocked;
+        assert!(app.world_mut().get_mut::<OutputInventory>(entity).unwrap().0.remove(&[stack(&content.coal, 1)]));
+        app.update();
+        assert_eq!(*app.world().get::<MachineState>(entity).unwrap(), MachineState::Idle);
+    }
+
+    #[test]
+    fn reloaded_inputs_move_what_the_recipe_no_longer_uses() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        world.get_mut::<InputInventory>(entity).unwrap().0.set_filter(ItemFilter::Any);
+        world.get_mut::<InputInventory>(entity).unwrap().0.add(&[stack(&content.coal, 2)]);
+        // smelting takes coal instead of ore now
+        let smelt = recipe(content.smelt.id, 10, &[stack(&content.coal, 1)], &[], &[stack(&content.plate, 1)]);
+        world.resource_mut::<RecipeList>().0.insert(smelt.id, smelt);
+        world.send_event(DefinitionsReloaded);
+        world.run_system_once(refresh_machines);
+        world.run_system_once(sync_input_filters);
+        let input = &world.get::<InputInventory>(entity).unwrap().0;
+        assert_eq!((input.count(&content.ore), input.count(&content.coal)), (0, 2));
+        assert!(!input.filter.accepts(&content.ore));
+        assert_eq!(world.get::<OutputInventory>(entity).unwrap().0.count(&content.ore), 3);
+        // the craft in progress keeps the ore it took
+        assert_eq!(world.get::<CraftInputs>(entity).unwrap().0, vec![stack(&content.ore, 2)]);
+    }
+
+    #[test]
+    fn reloaded_inputs_stay_when_the_output_is_full() {
+        let content = content();
+        let (mut world, entity) = world_with_machine(&content);
+        world.get_mut::<OutputInventory>(entity).unwrap().0.add(&[stack(&content.plate, 1)]);
+        let smelt = recipe(content.smelt.id, 10, &[stack(&content.coal, 1)], &[], &[stack(&content.plate, 1)]);
+        world.resource_mut::<RecipeList>().0.insert(smelt.id, smelt);
+        world.send_event(DefinitionsReloaded);
+        world.run_system_once(refresh_machines);
+        assert_eq!(world.get::<InputInventory>(entity).unwrap().0.count(&content.ore), 3);
+        assert_eq!(world.get::<OutputInventory>(entity).unwrap().0.count(&content.plate), 1);
+        assert_eq!(world.get::<SetRecipe>(entity).unwrap().0.as_ref().map(|r| r.inputs.clone()), Some(vec![stack(&content.coal, 1)]));
+    }
+}
@@ -1,32 +1,57 @@
 use bevy::prelude::*;
-use bevy_pancam::{PanCam, PanCamPlugin};
-use bevy_inspector_egui::quick::WorldInspectorPlugin;
 
 mod state;
 mod asset;
 mod ui;
+mod ids;
 mod item;
 mod recipe;
 mod machine;
 mod inventory;
+mod itemset;
+mod reservation;
+mod transaction;
+mod validation;
+mod pack;
+mod definitions;
+mod simulation;
+mod headless;
+mod cli;
+mod save;
+mod migration;
+mod scenario;
+#[cfg(test)]
+mod testing;
 
-fn main() {
-    App::new()
-        .add_plugins((
-            DefaultPlugins,
-            WorldInspectorPlugin::new(),
-            PanCamPlugin,
-            asset::AssetPlugin,
-            machine::MachinePlugin,
-        ))
-        .init_state::<state::AppState>()
-        .add_systems(Startup, spawn_camera)
-        .run();
-}
+fn main() -> AppExit {
+    let args = match cli::Args::parse() {
+        Ok(args) => args,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("usage: bevy-automation [--headless] [--ticks N] [--scenario FILE] [--load SAVE] [--save SAVE]");
+            return AppExit::error();
+        }
+    };
 
-fn spawn_camera(
-    mut commands: Commands
-) {
-    commands.spawn(Camera2dBundle::default())
-        .insert(PanCam::default());
+    let mut app = App::new();
+    if args.headless {
+        app.add_plugins((
+            MinimalPlugins,
+            bevy::log::LogPlugin::default(),
+            bevy::asset::AssetPlugin::default(),
+            bevy::state::app::StatesPlugin,
+            headless::HeadlessPlugin { ticks: args.ticks, save: args.save.clone() },
+        ));
+    } else {
+        app.add_plugins((
+            DefaultPlugins,
+            ui::PresentationPlugin,
+        ));
+    }
+    app.add_plugins((
+        simulation::SimulationPlugin,
+        scenario::ScenarioPlugin { path: args.scenario },
+        save::SavePlugin { load_on_start: args.load },
+    ))
+        .run()
 }
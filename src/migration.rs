@@ -0,0 +1,183 @@
+use crate::machine::*;
+use crate::save::*;
+
+// upgrades the text of a save written by version `from` to the layout of version `from + 1`
+pub struct Migration {
+    pub from: u32,
+    pub upgrade: fn(&str) -> Result<String, String>,
+}
+
+// one step per version, a save from any older version goes through every step after it
+pub const MIGRATIONS: &[Migration] = &[
+    Migration { from: 1, upgrade: v1_to_v2 },
+    Migration { from: 2, upgrade: v2_to_v3 },
+];
+
+// enough of any version to tell which one it is, version 1 had it at the top and later versions in the header
+#[derive(serde::Deserialize, Default)]
+struct VersionProbe {
+    #[serde(default)]
+    version: u32,
+    #[serde(default)]
+    header: HeaderProbe,
+}
+
+#[derive(serde::Deserialize, Default)]
+struct HeaderProbe {
+    #[serde(default)]
+    version: u32,
+}
+
+pub fn save_version(text: &str) -> Result<u32, String> {
+    let probe: VersionProbe = ron::from_str(text).map_err(|e| e.to_string())?;
+    match (probe.header.version, probe.version) {
+        (0, 0) => Err("the save has no version".to_string()),
+        (0, version) => Ok(version),
+        (version, _) => Ok(version),
+    }
+}
+
+// brings a save up to SAVE_VERSION, one version at a time
+pub fn migrate(mut text: String, mut version: u32) -> Result<String, String> {
+    if version > SAVE_VERSION {
+        return Err(format!("save version {} is newer than this game's {}", version, SAVE_VERSION));
+    }
+    while version < SAVE_VERSION {
+        let Some(migration) = MIGRATIONS.iter().find(|m| m.from == version) else {
+            return Err(format!("there is no way to upgrade saves from version {}", version));
+        };
+        text = (migration.upgrade)(&text).map_err(|e| format!("upgrading from version {}: {}", version, e))?;
+        version += 1;
+    }
+    Ok(text)
+}
+
+// the layouts of old versions stay here as they were written
+// they use the current types for the parts that haven't changed since, copy those in here once they do
+
+#[derive(serde::Deserialize)]
+struct SaveFileV1 {
+    tick: u64,
+    machines: Vec<SavedMachineV1>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SaveFileV2 {
+    header: SaveHeader,
+    tick: u64,
+    machines: Vec<SavedMachine>,
+}
+
+#[derive(serde::Deserialize)]
+struct SavedMachineV1 {
+    template: String,
+    position: [f32; 3],
+    recipe: Option<String>,
+    state: MachineState,
+    progress: CraftingTimer,
+    input: SavedInventory,
+    output: SavedInventory,
+}
+
+// version 2 added the header with the content packs, and the inputs of the current craft
+// version 1 saves don't know either, the packs are left empty and crafts have nothing to refund
+fn v1_to_v2(text: &str) -> Result<String, String> {
+    let old: SaveFileV1 = ron::from_str(text).map_err(|e| e.to_string())?;
+    let save = SaveFileV2 {
+        header: SaveHeader { version: 2, packs: Vec::new() },
+        tick: old.tick,
+        machines: old.machines
+            .into_iter()
+            .map(|m| SavedMachine {
+                template: m.template,
+                position: m.position,
+                recipe: m.recipe,
+                state: m.state,
+                progress: m.progress,
+                craft_inputs: Vec::new(),
+                input: m.input,
+                output: m.output,
+            })
+            .collect(),
+    };
+    ron::to_string(&save).map_err(|e| e.to_string())
+}
+
+// version 3 added storages, older saves didn't have any
+fn v2_to_v3(text: &str) -> Result<String, String> {
+    let old: SaveFileV2 = ron::from_str(text).map_err(|e| e.to_string())?;
+    let save = SaveFile {
+        header: SaveHeader { version: 3, packs: old.header.packs },
+        tick: old.tick,
+        machines: old.machines,
+        storages: Vec::new(),
+    };
+    ron::to_string(&save).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a save as version 1 of the game wrote it
+    const V1_SAVE: &str = r#"(
+        version: 1,
+        tick: 42,
+        machines: [
+            (
+                template: "base:extruder",
+                position: (1.0, 2.0, 0.0),
+                recipe: Some("base:iron_rod"),
+                state: Crafting,
+                progress: (elapsed: 10, duration: 60),
+                input: (slots: [Some((item: "base:iron_plate", size: 5)), None]),
+                output: (slots: [None]),
+            ),
+        ],
+    )"#;
+
+    #[test]
+    fn versions_are_found_in_old_and_new_saves() {
+        assert_eq!(save_version(V1_SAVE), Ok(1));
+        assert_eq!(save_version("(header: (version: 2, packs: []), tick: 0, machines: [])"), Ok(2));
+        assert!(save_version("(tick: 0, machines: [])").is_err());
+        assert!(save_version("not a save").is_err());
+    }
+
+    #[test]
+    fn version_1_saves_are_upgraded() {
+        let text = migrate(V1_SAVE.to_string(), 1).unwrap();
+        assert_eq!(save_version(&text), Ok(SAVE_VERSION));
+        let save: SaveFile = ron::from_str(&text).unwrap();
+        assert_eq!(save.header.version, SAVE_VERSION);
+        assert!(save.header.packs.is_empty());
+        assert_eq!(save.tick, 42);
+        assert!(save.storages.is_empty());
+        let machine = &save.machines[0];
+        assert_eq!(machine.template, "base:extruder");
+        assert_eq!(machine.recipe.as_deref(), Some("base:iron_rod"));
+        assert_eq!(machine.state, MachineState::Crafting);
+        assert_eq!(machine.progress, CraftingTimer { elapsed: 10, duration: 60 });
+        assert!(machine.craft_inputs.is_empty());
+        assert_eq!(machine.input.slots[0].as_ref().map(|s| (s.item.as_str(), s.size)), Some(("base:iron_plate", 5)));
+    }
+
+    #[test]
+    fn current_saves_are_left_alone() {
+        let text = format!("(header: (version: {}, packs: []), tick: 0, machines: [], storages: [])", SAVE_VERSION);
+        assert_eq!(migrate(text.clone(), SAVE_VERSION), Ok(text));
+    }
+
+    #[test]
+    fn unknown_versions_are_errors() {
+        assert!(migrate(String::new(), SAVE_VERSION + 1).is_err());
+        assert!(migrate(String::new(), 0).is_err());
+    }
+
+    #[test]
+    fn every_old_version_has_a_migration() {
+        for version in 1..SAVE_VERSION {
+            assert!(MIGRATIONS.iter().any(|m| m.from == version), "no migration from version {}", version);
+        }
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+
+use crate::ids::*;
+
+// directory in the asset root that content packs are discovered from, i.e. `assets/mods/<pack>/`
+// packs live inside the asset root so the asset server, and hot reloading, see their files like any other
+pub const MODS_FOLDER: &str = "mods";
+// folders of a pack that hold definition files
+pub const CONTENT_FOLDERS: [&str; 3] = ["items", "recipes", "machines"];
+const MANIFEST_FILE: &str = "pack.ron";
+
+// pack.ron at the root of every content pack
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct PackManifest {
+    // also the namespace of ids the pack writes without one
+    pub name: String,
+    pub version: String,
+    // names of the packs that have to be loaded before this one
+    #[serde(default)]
+    pub dependencies: Vec<String>,
+    // packs that don't depend on each other are loaded lowest first
+    #[serde(default)]
+    pub load_order: i32,
+}
+
+#[derive(Clone, Debug)]
+pub struct ContentPack {
+    pub manifest: PackManifest,
+    // asset path of the pack directory, empty for the base game
+    pub root: String,
+}
+
+impl ContentPack {
+    // the base game is a pack too, it owns the content folders in the asset root
+    pub fn base() -> Self {
+        ContentPack {
+            manifest: PackManifest {
+                name: DEFAULT_NAMESPACE.to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                dependencies: Vec::new(),
+                load_order: i32::MIN,
+            },
+            root: String::new(),
+        }
+    }
+
+    pub fn namespace(&self) -> &str {
+        &self.manifest.name
+    }
+
+    // asset path of a file inside the pack
+    pub fn path(&self, path: &str) -> String {
+        if self.root.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}/{}", self.root, path)
+        }
+    }
+
+    // asset paths of the content folders the pack actually has
+    pub fn content_folders(&self, asset_root: &Path) -> Vec<String> {
+        CONTENT_FOLDERS
+            .iter()
+            .map(|folder| self.path(folder))
+            .filter(|path| asset_root.join(path).is_dir())
+            .collect()
+    }
+
+    // hash of every file in the pack's content folders, changes whenever a definition does
+    // FNV-1a so saves written by one build can be compared by another
+    pub fn fingerprint(&self, asset_root: &Path) -> u64 {
+        let mut files = Vec::<PathBuf>::new();
+        for folder in self.content_folders(asset_root) {
+            collect_files(&asset_root.join(folder), &mut files);
+        }
+        files.sort();
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for file in files.iter() {
+            let name = file.strip_prefix(asset_root).unwrap_or(file).to_string_lossy().into_owned();
+            let contents = fs::read(file).unwrap_or_default();
+            for byte in name.bytes().chain([0]).chain(contents).chain([0]) {
+                hash = (hash ^ u64::from(byte)).wrapping_mul(0x0100_0000_01b3);
+            }
+        }
+        hash
+    }
+}
+
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for path in entries.flatten().map(|e| e.path()) {
+        if path.is_dir() {
+            collect_files(&path, files);
+        } else {
+            files.push(path);
+        }
+    }
+}
+
+// every content pack that is loaded, in load order with the base game first
+#[derive(Resource, Clone, Debug)]
+pub struct ContentPacks(pub Vec<ContentPack>);
+
+impl ContentPacks {
+    // the base game and every loadable pack in the mods folder
+    pub fn discover(asset_root: &Path) -> Self {
+        let mods_root = asset_root.join(MODS_FOLDER);
+        let mut found = Vec::<ContentPack>::new();
+        let mut dirs: Vec<_> = fs::read_dir(&mods_root)
+            .map(|entries| entries.flatten().map(|e| e.path()).filter(|p| p.is_dir()).collect())
+            .unwrap_or_default();
+        dirs.sort();
+        for dir in dirs.into_iter() {
+            let Some(dir_name) = dir.file_name().and_then(|n| n.to_str()) else {
+                continue;
+            };
+            let manifest = match read_manifest(&dir.join(MANIFEST_FILE)) {
+                Ok(manifest) => manifest,
+                Err(e) => {
+                    error!("Not loading content pack in {}: {}", dir.display(), e);
+                    continue;
+                }
+            };
+            if found.iter().any(|p| p.manifest.name == manifest.name) || manifest.name == DEFAULT_NAMESPACE {
+                error!("Not loading content pack in {}: there already is a pack named `{}`", dir.display(), manifest.name);
+                continue;
+            }
+            found.push(ContentPack { manifest, root: format!("{}/{}", MODS_FOLDER, dir_name) });
+        }
+        ContentPacks(order_packs(found))
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &ContentPack> {
+        self.0.iter()
+    }
+
+    pub fn get(&self, index: usize) -> &ContentPack {
+        &self.0[index]
+    }
+
+    // index of the pack an asset file belongs to
+    pub fn pack_of(&self, path: &str) -> usize {
+        self.0
+            .iter()
+            .position(|pack| !pack.root.is_empty() && path.starts_with(&format!("{}/", pack.root)))
+            .unwrap_or(0)
+    }
+}
+
+fn read_manifest(path: &Path) -> Result<PackManifest, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", MANIFEST_FILE, e))?;
+    let manifest: PackManifest = ron::from_str(&text).map_err(|e| format!("invalid {}: {}", MANIFEST_FILE, e))?;
+    if manifest.name.is_empty() || manifest.name.contains(':') {
+        return Err(format!("invalid pack name `{}`, it can't be empty or contain `:`", manifest.name));
+    }
+    Ok(manifest)
+}
+
+// put every pack after its dependencies, dropping packs whose dependencies can't be loaded
+fn order_packs(mut remaining: Vec<ContentPack>) -> Vec<ContentPack> {
+    let known: HashSet<String> = remaining.iter().map(|p| p.manifest.name.clone()).collect();
+    let mut ordered = vec![ContentPack::base()];
+    let mut loaded: HashSet<String> = HashSet::from([DEFAULT_NAMESPACE.to_string()]);
+    loop {
+        let next = remaining
+            .iter()
+            .enumerate()
+            .filter(|(_, p)| p.manifest.dependencies.iter().all(|d| loaded.contains(d)))
+            .min_by(|(_, a), (_, b)| {
+                (a.manifest.load_order, &a.manifest.name).cmp(&(b.manifest.load_order, &b.manifest.name))
+            })
+            .map(|(i, _)| i);
+        let Some(i) = next else {
+            break;
+        };
+        let pack = remaining.remove(i);
+        loaded.insert(pack.manifest.name.clone());
+        ordered.push(pack);
+    }
+    for pack in remaining.iter() {
+        let missing: Vec<&String> = pack.manifest.dependencies
+            .iter()
+            .filter(|d| !known.contains(*d) && d.as_str() != DEFAULT_NAMESPACE)
+            .collect();
+        if missing.is_empty() {
+            error!("Not loading content pack {}: it depends on packs that can't be loaded, or on itself", pack.manifest.name);
+        } else {
+            error!("Not loading content pack {}: missing dependencies {:?}", pack.manifest.name, missing);
+        }
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pack(name: &str, dependencies: &[&str], load_order: i32) -> ContentPack {
+        ContentPack {
+            manifest: PackManifest {
+                name: name.to_string(),
+                version: "1.0".to_string(),
+                dependencies: dependencies.iter().map(|d| d.to_string()).collect(),
+                load_order,
+            },
+            root: format!("{}/{}", MODS_FOLDER, name),
+        }
+    }
+
+    fn names(packs: &[ContentPack]) -> Vec<&str> {
+        packs.iter().map(|p| p.manifest.name.as_str()).collect()
+    }
+
+    #[test]
+    fn packs_load_after_their_dependencies() {
+        let ordered = order_packs(vec![pack("a", &["c"], 0), pack("b", &[], 5), pack("c", &["b"], 0)]);
+        assert_eq!(names(&ordered), vec![DEFAULT_NAMESPACE, "b", "c", "a"]);
+    }
+
+    #[test]
+    fn independent_packs_load_by_order_then_name() {
+        let ordered = order_packs(vec![pack("z", &[], 0), pack("y", &[DEFAULT_NAMESPACE], 0), pack("x", &[], 1)]);
+        assert_eq!(names(&ordered), vec![DEFAULT_NAMESPACE, "y", "z", "x"]);
+    }
+
+    #[test]
+    fn cycles_and_missing_dependencies_are_dropped() {
+        let ordered = order_packs(vec![
+            pack("a", &["b"], 0),
+            pack("b", &["a"], 0),
+            pack("c", &["c"], 0),
+            pack("d", &["missing"], 0),
+            pack("e", &["a"], 0),
+            pack("f", &[], 0),
+        ]);
+        assert_eq!(names(&ordered), vec![DEFAULT_NAMESPACE, "f"]);
+    }
+
+    #[test]
+    fn files_belong_to_the_pack_they_are_in() {
+        let packs = ContentPacks(order_packs(vec![pack("gears", &[], 0), pack("gear", &[], 0)]));
+        assert_eq!(packs.get(packs.pack_of("mods/gears/items/a.item.ron")).namespace(), "gears");
+        assert_eq!(packs.get(packs.pack_of("mods/gear/items/a.item.ron")).namespace(), "gear");
+        assert_eq!(packs.pack_of("items/a.item.ron"), 0);
+    }
+}
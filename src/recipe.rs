@@ -1,58 +1,267 @@
+use std::cmp::min;
 use std::collections::HashMap;
 use bevy::prelude::*;
 
 use crate::item::*;
+use crate::inventory::*;
+use crate::ids::*;
 
 #[derive(serde::Deserialize, Asset, TypePath, Clone)]
 pub struct RecipeTemplate {
     pub name: String,
+    #[serde(rename = "id")]
+    pub key: String,
+    #[serde(skip)]
     pub id: u16,
-    pub duration: f32,
-    pub inputs: HashMap<u16, u16>,
-    pub outputs: HashMap<u16, u16>
+    // machines that craft this category get the recipe without listing it, e.g. "smelting"
+    // categories have no namespace, so a pack's recipes can go into another pack's machines
+    #[serde(default)]
+    pub category: Option<String>,
+    // crafting time at speed 1, in whole ticks so every run crafts exactly as long
+    pub ticks: u32,
+    pub inputs: HashMap<String, ItemCount>,
+    // ingredients that accept any item with the tag, e.g. {"plate": 2}
+    #[serde(default)]
+    pub tag_inputs: HashMap<String, ItemCount>,
+    pub outputs: HashMap<String, ItemCount>
+}
+
+// changes a pack makes to a recipe defined by another pack, fields left out keep their value
+// ingredient amounts replace the old ones, an amount of 0 removes the ingredient
+#[derive(serde::Deserialize, Asset, TypePath, Clone)]
+pub struct RecipePatch {
+    #[serde(rename = "id")]
+    pub key: String,
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub category: Option<String>,
+    // takes the recipe out of its category, applied before `category`
+    #[serde(default)]
+    pub clear_category: bool,
+    #[serde(default)]
+    pub ticks: Option<u32>,
+    #[serde(default)]
+    pub inputs: HashMap<String, ItemCount>,
+    #[serde(default)]
+    pub tag_inputs: HashMap<String, ItemCount>,
+    #[serde(default)]
+    pub outputs: HashMap<String, ItemCount>,
+}
+
+impl RecipePatch {
+    pub fn apply(&self, template: &mut RecipeTemplate) {
+        if let Some(name) = &self.name {
+            template.name = name.clone();
+        }
+        if self.clear_category {
+            template.category = None;
+        }
+        if let Some(category) = &self.category {
+            template.category = Some(category.clone());
+        }
+        if let Some(ticks) = self.ticks {
+            template.ticks = ticks;
+        }
+        patch_amounts(&mut template.inputs, &self.inputs);
+        patch_amounts(&mut template.tag_inputs, &self.tag_inputs);
+        patch_amounts(&mut template.outputs, &self.outputs);
+    }
+}
+
+fn patch_amounts(amounts: &mut HashMap<String, ItemCount>, patch: &HashMap<String, ItemCount>) {
+    for (key, amount) in patch.iter() {
+        if *amount == 0 {
+            amounts.remove(key);
+        } else {
+            amounts.insert(key.clone(), *amount);
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct TagIngredient {
+    pub tag: String,
+    pub amount: ItemCount,
 }
 
 #[derive(Clone)]
 pub struct Recipe {
     pub name: String,
+    pub key: String,
     pub id: u16,
-    pub duration: f32,
+    // crafting time at speed 1
+    pub ticks: u32,
     pub inputs: Vec<ItemStack>,
+    pub tag_inputs: Vec<TagIngredient>,
     pub outputs: Vec<ItemStack>
 }
 
 impl Recipe {
     pub fn from_template(
         template: &RecipeTemplate,
-        item_types: &ItemTypeList
-    ) -> Recipe {
+        item_types: &ItemTypeList,
+        registry: &IdRegistry,
+    ) -> Result<Recipe, String> {
+        let find_item = |key: &String| {
+            registry.items.get(key)
+                .and_then(|id| item_types.get(id))
+                .ok_or_else(|| format!("unknown item `{}` in recipe `{}`", key, template.key))
+        };
         let mut inputs = Vec::<ItemStack>::new();
         let mut outputs = Vec::<ItemStack>::new();
-        for (type_id, amount) in template.inputs.iter() {
+        for (key, amount) in template.inputs.iter() {
             inputs.push(
                 ItemStack {
-                    item_type: item_types.0.get(type_id).unwrap().clone(),
+                    item_type: find_item(key)?.clone(),
                     size: *amount
                 }
             );
         }
-        for (type_id, amount) in template.outputs.iter() {
+        for (key, amount) in template.outputs.iter() {
             outputs.push(
                 ItemStack {
-                    item_type: item_types.0.get(type_id).unwrap().clone(),
+                    item_type: find_item(key)?.clone(),
                     size: *amount
                 }
             );
         }
-        Recipe {
+        let mut tag_inputs: Vec<TagIngredient> = template.tag_inputs
+            .iter()
+            .map(|(tag, amount)| TagIngredient { tag: tag.clone(), amount: *amount })
+            .collect();
+        inputs.sort_by_key(|s| s.item_type.id);
+        tag_inputs.sort_by(|a, b| a.tag.cmp(&b.tag));
+        outputs.sort_by_key(|s| s.item_type.id);
+        Ok(Recipe {
             name: template.name.clone(),
+            key: template.key.clone(),
             id: template.id,
-            duration: template.duration,
+            ticks: template.ticks,
             inputs,
+            tag_inputs,
             outputs,
+        })
+    }
+
+    // pick the concrete stacks to consume from a container, or None if it doesn't hold enough
+    // exact inputs are claimed first, then tag ingredients in tag order. for each tag the lowest
+    // id item that can cover the whole amount is used, otherwise matching items are combined by id
+    pub fn resolve_inputs(&self, container: &dyn ItemContainer) -> Option<Vec<ItemStack>> {
+        let mut claimed = self.inputs.clone();
+        if !container.contains(&claimed) {
+            return None;
         }
+        let mut candidates: Vec<ItemType> = Vec::new();
+        for stack in container.stacks().into_iter() {
+            if !candidates.contains(&stack.item_type) {
+                candidates.push(stack.item_type);
+            }
+        }
+        candidates.sort_by_key(|t| t.id);
+        for ingredient in self.tag_inputs.iter() {
+            let unclaimed = |item_type: &ItemType, claimed: &[ItemStack]| {
+                let taken = saturating_sum(
+                    claimed.iter().filter(|s| s.item_type == *item_type).map(|s| s.size)
+                );
+                container.count(item_type).saturating_sub(taken)
+            };
+            let matching: Vec<&ItemType> = candidates.iter().filter(|t| t.has_tag(&ingredient.tag)).collect();
+            if let Some(item_type) = matching.iter().find(|t| unclaimed(t, &claimed) >= ingredient.amount) {
+                claimed.push(ItemStack::new((*item_type).clone(), ingredient.amount));
+                continue;
+            }
+            let mut remaining = ingredient.amount;
+            for item_type in matching.iter() {
+                let amount = min(unclaimed(item_type, &claimed), remaining);
+                if amount > 0 {
+                    claimed.push(ItemStack::new((*item_type).clone(), amount));
+                    remaining -= amount;
+                }
+                if remaining == 0 {
+                    break;
+                }
+            }
+            if remaining > 0 {
+                return None;
+            }
+        }
+        Some(claimed)
     }
 }
 
 #[derive(Resource)]
 pub struct RecipeList(pub HashMap<u16, Recipe>);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    fn metals() -> [ItemType; 3] {
+        [1, 2, 3].map(|id| tagged_item(id, 10, &["metal"]))
+    }
+
+    #[test]
+    fn tag_uses_the_lowest_id_item_that_covers_it() {
+        let [copper, iron, tin] = metals();
+        let mut inventory = Inventory::new(3);
+        inventory.add(&[stack(&tin, 5), stack(&copper, 2), stack(&iron, 5)]);
+        let recipe = recipe(1, 1, &[], &[("metal", 4)], &[]);
+        assert_eq!(recipe.resolve_inputs(&inventory), Some(vec![stack(&iron, 4)]));
+    }
+
+    #[test]
+    fn exact_inputs_are_claimed_before_tags() {
+        let [copper, iron, tin] = metals();
+        let mut inventory = Inventory::new(3);
+        inventory.add(&[stack(&copper, 2), stack(&iron, 5), stack(&tin, 5)]);
+        let recipe = recipe(1, 1, &[stack(&iron, 3)], &[("metal", 4)], &[]);
+        assert_eq!(recipe.resolve_inputs(&inventory), Some(vec![stack(&iron, 3), stack(&tin, 4)]));
+    }
+
+    #[test]
+    fn tag_combines_items_by_id_when_none_covers_it() {
+        let [copper, iron, tin] = metals();
+        let mut inventory = Inventory::new(3);
+        inventory.add(&[stack(&tin, 5), stack(&iron, 5), stack(&copper, 2)]);
+        let enough = recipe(1, 1, &[], &[("metal", 8)], &[]);
+        assert_eq!(enough.resolve_inputs(&inventory), Some(vec![stack(&copper, 2), stack(&iron, 5), stack(&tin, 1)]));
+        let too_much = recipe(2, 1, &[], &[("metal", 13)], &[]);
+        assert_eq!(too_much.resolve_inputs(&inventory), None);
+    }
+
+    #[test]
+    fn selection_doesnt_depend_on_the_layout() {
+        let [copper, iron, tin] = metals();
+        let recipe = recipe(1, 1, &[stack(&copper, 1)], &[("metal", 6)], &[]);
+        let mut a = Inventory::new(3);
+        a.add(&[stack(&copper, 3), stack(&iron, 4), stack(&tin, 4)]);
+        let mut b = Inventory::new(4);
+        b.add(&[stack(&tin, 4), stack(&iron, 4), stack(&copper, 3)]);
+        b.swap_slots(0, 3);
+        assert_eq!(recipe.resolve_inputs(&a), recipe.resolve_inputs(&b));
+    }
+
+    #[test]
+    fn patches_can_clear_or_replace_the_category() {
+        let mut template = RecipeTemplate {
+            name: "Gear".to_string(),
+            key: "base:gear".to_string(),
+            id: 0,
+            category: Some("pressing".to_string()),
+            ticks: 60,
+            inputs: HashMap::new(),
+            tag_inputs: HashMap::new(),
+            outputs: HashMap::new(),
+        };
+        let mut patch: RecipePatch = ron::from_str(r#"(id: "base:gear", clear_category: true)"#).unwrap();
+        patch.apply(&mut template);
+        assert_eq!(template.category, None);
+        // a new category replaces the cleared one
+        patch.category = Some("stamping".to_string());
+        patch.apply(&mut template);
+        assert_eq!(template.category.as_deref(), Some("stamping"));
+        assert_eq!(template.ticks, 60);
+    }
+}
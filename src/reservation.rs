@@ -0,0 +1,109 @@
+use std::cmp::min;
+use std::time::Duration;
+
+use bevy::prelude::*;
+
+// empty slots kept free in a container for the entity that is going to fill them
+#[derive(Clone, Debug)]
+pub struct Reservation {
+    pub owner: Entity,
+    pub slots: usize,
+    // simulated time after which the reservation is dropped, see CurrentTick::elapsed
+    pub expires_at: Option<Duration>,
+}
+
+// reservations held on a single container
+#[derive(Clone, Default, Debug)]
+pub struct Reservations {
+    entries: Vec<Reservation>,
+}
+
+impl Reservations {
+    pub fn push(&mut self, owner: Entity, slots: usize, expires_at: Option<Duration>) {
+        self.entries.push(Reservation { owner, slots, expires_at });
+    }
+
+    pub fn cancel_all(&mut self, owner: Entity) {
+        self.entries.retain(|r| r.owner != owner);
+    }
+
+    pub fn has_expired(&self, now: Duration) -> bool {
+        self.entries.iter().any(|r| r.expires_at.is_some_and(|t| t <= now))
+    }
+
+    // drop timed out reservations, returns how many were removed
+    pub fn expire(&mut self, now: Duration) -> usize {
+        let len = self.entries.len();
+        self.entries.retain(|r| r.expires_at.is_none_or(|t| t > now));
+        len - self.entries.len()
+    }
+
+    // slots reserved by everyone except `owner`
+    pub fn reserved_slots(&self, owner: Option<Entity>) -> usize {
+        self.entries
+            .iter()
+            .filter(|r| Some(r.owner) != owner)
+            .map(|r| r.slots)
+            .sum()
+    }
+
+    // the owner used up `slots` of its reserved slots
+    pub fn consume_slots(&mut self, owner: Entity, slots: usize) {
+        let mut to_consume = slots;
+        for r in self.entries.iter_mut().filter(|r| r.owner == owner) {
+            let amount = min(r.slots, to_consume);
+            r.slots -= amount;
+            to_consume -= amount;
+        }
+        self.entries.retain(|r| r.slots > 0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::item::*;
+    use crate::inventory::*;
+    use crate::testing::*;
+
+    #[test]
+    fn reserved_slots_are_kept_free_for_their_owner() {
+        let plate = item(1, 10);
+        let (owner, other) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut inventory = Inventory::new(2);
+        assert!(inventory.reserve_slots(owner, 1, None));
+        assert!(!inventory.reserve_slots(other, 2, None));
+        // anyone else only gets the slot nobody reserved
+        let leftovers = inventory.add_as(Some(other), &[stack(&plate, 20)]);
+        assert_eq!(leftovers.iter().map(|s| s.size).sum::<ItemCount>(), 10);
+        assert_eq!(inventory.available_slots(Some(other)), 0);
+        // the owner can still use its slot, which uses up the reservation
+        assert!(inventory.add_as(Some(owner), &[stack(&plate, 10)]).is_empty());
+        assert_eq!(inventory.reservations.reserved_slots(None), 0);
+    }
+
+    #[test]
+    fn cancelled_reservations_free_their_slots() {
+        let plate = item(1, 10);
+        let (owner, other) = (Entity::from_raw(1), Entity::from_raw(2));
+        let mut inventory = Inventory::new(1);
+        assert!(inventory.reserve_slots(owner, 1, None));
+        assert_eq!(inventory.add_as(Some(other), &[stack(&plate, 10)]), vec![stack(&plate, 10)]);
+        inventory.cancel_reservations(owner);
+        assert!(inventory.add_as(Some(other), &[stack(&plate, 10)]).is_empty());
+    }
+
+    #[test]
+    fn reservations_expire_at_their_time() {
+        let owner = Entity::from_raw(1);
+        let mut reservations = Reservations::default();
+        reservations.push(owner, 1, Some(Duration::from_secs(2)));
+        reservations.push(owner, 1, None);
+        assert!(!reservations.has_expired(Duration::from_secs(1)));
+        assert_eq!(reservations.expire(Duration::from_secs(1)), 0);
+        assert!(reservations.has_expired(Duration::from_secs(2)));
+        assert_eq!(reservations.expire(Duration::from_secs(2)), 1);
+        // the one without a time stays until it is used or cancelled
+        assert_eq!(reservations.reserved_slots(None), 1);
+    }
+}
@@ -0,0 +1,678 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use bevy::prelude::*;
+
+use crate::state::AppState;
+use crate::ids::*;
+use crate::item::*;
+use crate::recipe::*;
+use crate::machine::*;
+use crate::inventory::*;
+use crate::simulation::*;
+use crate::pack::*;
+use crate::migration::*;
+use crate::asset::AssetRoot;
+
+// bump when the layout of SaveFile changes, and add a migration from the old version
+pub const SAVE_VERSION: u32 = 3;
+pub const QUICKSAVE_PATH: &str = "saves/quicksave.save.ron";
+
+pub struct SavePlugin {
+    // save to load once the game has started
+    pub load_on_start: Option<PathBuf>,
+}
+
+impl Plugin for SavePlugin {
+    fn build(&self, app: &mut App) {
+        app.add_event::<SaveGame>()
+            .add_event::<LoadGame>();
+        app.init_resource::<LoadReport>();
+        // saves capture the state at the end of a frame, loads are applied before the frame's ticks run
+        app.add_systems(Last, save_game.run_if(in_state(AppState::InGame)));
+        app.add_systems(Update, load_game.before(run_sim_ticks).run_if(in_state(AppState::InGame)));
+        if let Some(path) = self.load_on_start.clone() {
+            app.add_systems(
+                OnEnter(AppState::InGame),
+                move |mut loads: EventWriter<LoadGame>| {
+                    loads.send(LoadGame(path.clone()));
+                }
+            );
+        }
+    }
+}
+
+#[derive(Event, Clone, Debug)]
+pub struct SaveGame(pub PathBuf);
+
+// replaces every machine in the world with the ones in the save
+#[derive(Event, Clone, Debug)]
+pub struct LoadGame(pub PathBuf);
+
+// items, recipes and machines are stored by their string ids, runtime ids can change between runs
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SaveFile {
+    pub header: SaveHeader,
+    pub tick: u64,
+    pub machines: Vec<SavedMachine>,
+    pub storages: Vec<SavedStorage>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SaveHeader {
+    pub version: u32,
+    // the content the save was made with, to tell what changed since
+    pub packs: Vec<PackFingerprint>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct PackFingerprint {
+    pub name: String,
+    pub version: String,
+    pub fingerprint: u64,
+}
+
+impl PackFingerprint {
+    pub fn of_packs(packs: &ContentPacks, asset_root: &Path) -> Vec<PackFingerprint> {
+        packs
+            .iter()
+            .map(|pack| PackFingerprint {
+                name: pack.manifest.name.clone(),
+                version: pack.manifest.version.clone(),
+                fingerprint: pack.fingerprint(asset_root),
+            })
+            .collect()
+    }
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SavedMachine {
+    pub template: String,
+    pub position: [f32; 3],
+    pub recipe: Option<String>,
+    pub state: MachineState,
+    pub progress: CraftingTimer,
+    pub craft_inputs: Vec<SavedStack>,
+    pub input: SavedInventory,
+    pub output: SavedInventory,
+}
+
+// storages keep their totals, not how they were laid out in slots
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SavedStorage {
+    pub position: [f32; 3],
+    pub backend: StorageBackend,
+    pub slots: usize,
+    pub contents: Vec<SavedStack>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug)]
+pub struct SavedInventory {
+    pub slots: Vec<Option<SavedStack>>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, PartialEq, Eq, Debug)]
+pub struct SavedStack {
+    pub item: String,
+    pub size: ItemCount,
+}
+
+impl SavedInventory {
+    // filters and reservations are not saved, filters follow the recipe and reservations are short lived
+    pub fn capture(inventory: &Inventory) -> Self {
+        SavedInventory {
+            slots: inventory.slots.iter().map(|slot| slot.as_ref().map(SavedStack::capture)).collect(),
+        }
+    }
+
+    pub fn restore(&self, item_types: &ItemTypeList, registry: &mut IdRegistry, report: &mut LoadReport) -> Inventory {
+        let mut inventory = Inventory::new(self.slots.len());
+        for (slot, saved) in inventory.slots.iter_mut().zip(self.slots.iter()) {
+            if let Some(saved) = saved {
+                *slot = saved.restore(item_types, registry, report);
+            }
+        }
+        inventory
+    }
+}
+
+impl SavedStorage {
+    pub fn capture(storage: &Storage, transform: &Transform) -> Self {
+        let mut contents: Vec<SavedStack> = storage.stacks().iter().map(SavedStack::capture).collect();
+        // hashed storages don't keep an order
+        contents.sort_by(|a, b| a.item.cmp(&b.item).then(b.size.cmp(&a.size)));
+        SavedStorage {
+            position: transform.translation.to_array(),
+            backend: storage.backend(),
+            slots: storage.capacity(),
+            contents,
+        }
+    }
+
+    pub fn restore(&self, item_types: &ItemTypeList, registry: &mut IdRegistry, report: &mut LoadReport) -> Storage {
+        let mut storage = Storage::new(self.backend, self.slots);
+        let stacks: Vec<ItemStack> = self.contents.iter().filter_map(|s| s.restore(item_types, registry, report)).collect();
+        for stack in storage.insert(&stacks) {
+            report.lost.push(format!("{} of `{}` that didn't fit back into the storage at {:?}", stack.size, stack.item_type.key, self.position));
+        }
+        storage
+    }
+}
+
+impl SavedStack {
+    pub fn capture(stack: &ItemStack) -> Self {
+        SavedStack { item: stack.item_type.key.clone(), size: stack.size }
+    }
+
+    // items that were removed from the content are kept as placeholders, they are only lost if there's no id left for one
+    pub fn restore(&self, item_types: &ItemTypeList, registry: &mut IdRegistry, report: &mut LoadReport) -> Option<ItemStack> {
+        if let Some(item_type) = registry.items.get(&self.item).and_then(|id| item_types.get(id)) {
+            return Some(ItemStack::new(item_type.clone(), self.size));
+        }
+        let Some(id) = registry.items.intern(&self.item) else {
+            report.lost.push(format!("{} of unknown item `{}`", self.size, self.item));
+            return None;
+        };
+        report.placeholder_items.push(format!("{} of unknown item `{}`", self.size, self.item));
+        Some(ItemStack::new(placeholder_item(&self.item, id), self.size))
+    }
+}
+
+// stands in for an item that was removed from the content
+// it keeps the item's key, so saving writes it back and a reload that brings the item back replaces it
+fn placeholder_item(key: &str, id: u16) -> ItemType {
+    ItemType {
+        name: format!("Unknown item {}", key),
+        key: key.to_string(),
+        id,
+        // the real max stack is unknown, a placeholder stack is never split
+        max_stack: ItemCount::MAX,
+        tags: Vec::new(),
+        category: String::new(),
+    }
+}
+
+// stands in for a machine whose template was removed from the content
+// it keeps everything the save had, so saving again and bringing the content back restores the machine
+#[derive(Component, Clone, Debug)]
+pub struct MachinePlaceholder(pub SavedMachine);
+
+// what changed between the save and the game it was loaded into
+#[derive(Resource, Default, Debug)]
+pub struct LoadReport {
+    pub migrated_from: Option<u32>,
+    pub content_changes: Vec<String>,
+    pub placeholders: Vec<String>,
+    pub placeholder_items: Vec<String>,
+    // machines whose recipe was removed from the content or that can't craft it anymore, they are left without one
+    pub cleared_recipes: Vec<String>,
+    pub refunds: Vec<String>,
+    pub lost: Vec<String>,
+}
+
+impl LoadReport {
+    pub fn print(&self) {
+        if let Some(version) = self.migrated_from {
+            println!("Upgraded the save from version {} to {}", version, SAVE_VERSION);
+        }
+        for (title, entries) in [
+            ("Content changed since the save", &self.content_changes),
+            ("Machines kept as placeholders", &self.placeholders),
+            ("Items kept as placeholders", &self.placeholder_items),
+            ("Recipes cleared", &self.cleared_recipes),
+            ("Crafts refunded", &self.refunds),
+            ("Lost", &self.lost),
+        ] {
+            if entries.is_empty() {
+                continue;
+            }
+            warn!("{}:", title);
+            for entry in entries.iter() {
+                warn!("  {}", entry);
+            }
+        }
+    }
+
+    fn compare_packs(&mut self, saved: &[PackFingerprint], current: &[PackFingerprint]) {
+        // saves from before packs were recorded
+        if saved.is_empty() {
+            self.content_changes.push("the save doesn't say which content packs it was made with".to_string());
+            return;
+        }
+        for old in saved.iter() {
+            match current.iter().find(|p| p.name == old.name) {
+                None => self.content_changes.push(format!("pack `{}` is not loaded anymore", old.name)),
+                Some(new) if new.version != old.version => self.content_changes.push(
+                    format!("pack `{}` was version {}, now {}", old.name, old.version, new.version)
+                ),
+                Some(new) if new.fingerprint != old.fingerprint => self.content_changes.push(
+                    format!("the content of pack `{}` changed", old.name)
+                ),
+                Some(_) => (),
+            }
+        }
+        for new in current.iter().filter(|p| !saved.iter().any(|old| old.name == p.name)) {
+            self.content_changes.push(format!("pack `{}` was added", new.name));
+        }
+    }
+}
+
+pub fn write_save(path: &Path, save: &SaveFile) -> Result<(), String> {
+    let text = ron::ser::to_string_pretty(save, ron::ser::PrettyConfig::default())
+        .map_err(|e| format!("can't write save: {}", e))?;
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).map_err(|e| format!("can't create {}: {}", dir.display(), e))?;
+    }
+    fs::write(path, text).map_err(|e| format!("can't write {}: {}", path.display(), e))
+}
+
+// older versions are upgraded, the version the save was written with comes back with it
+pub fn read_save(path: &Path) -> Result<(SaveFile, u32), String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("can't read {}: {}", path.display(), e))?;
+    let invalid = |e: String| format!("invalid save {}: {}", path.display(), e);
+    let version = save_version(&text).map_err(invalid)?;
+    let text = migrate(text, version).map_err(invalid)?;
+    let save: SaveFile = ron::from_str(&text).map_err(|e| invalid(e.to_string()))?;
+    Ok((save, version))
+}
+
+type SavedMachineQuery<'a> = (
+    &'a Machine,
+    &'a SetRecipe,
+    &'a MachineState,
+    &'a CraftingTimer,
+    &'a CraftInputs,
+    &'a InputInventory,
+    &'a OutputInventory,
+    &'a Transform,
+);
+
+pub fn save_game(
+    mut saves: EventReader<SaveGame>,
+    tick: Res<CurrentTick>,
+    packs: Res<ContentPacks>,
+    asset_root: Res<AssetRoot>,
+    q: Query<SavedMachineQuery>,
+    placeholders: Query<&MachinePlaceholder>,
+    storages: Query<(&Storage, &Transform)>,
+) {
+    for SaveGame(path) in saves.read() {
+        let mut machines: Vec<SavedMachine> = q
+            .iter()
+            .map(|(machine, recipe, state, timer, craft_inputs, input, output, transform)| SavedMachine {
+                template: machine.0.key.clone(),
+                position: transform.translation.to_array(),
+                recipe: recipe.0.as_ref().map(|r| r.key.clone()),
+                state: *state,
+                progress: *timer,
+                craft_inputs: craft_inputs.0.iter().map(SavedStack::capture).collect(),
+                input: SavedInventory::capture(&input.0),
+                output: SavedInventory::capture(&output.0),
+            })
+            .chain(placeholders.iter().map(|p| p.0.clone()))
+            .collect();
+        // same world, same file
+        machines.sort_by(|a, b| {
+            a.position.partial_cmp(&b.position).unwrap_or(std::cmp::Ordering::Equal).then(a.template.cmp(&b.template))
+        });
+        let mut storages: Vec<SavedStorage> = storages
+            .iter()
+            .map(|(storage, transform)| SavedStorage::capture(storage, transform))
+            .collect();
+        storages.sort_by(|a, b| a.position.partial_cmp(&b.position).unwrap_or(std::cmp::Ordering::Equal));
+        let header = SaveHeader { version: SAVE_VERSION, packs: PackFingerprint::of_packs(&packs, asset_root.path()) };
+        let save = SaveFile { header, tick: tick.0, machines, storages };
+        match write_save(path, &save) {
+            Ok(()) => println!("Saved {} machine(s) and {} storage(s) to {}", save.machines.len(), save.storages.len(), path.display()),
+            Err(e) => error!("Saving failed: {}", e),
+        }
+    }
+}
+
+// everything a load replaces
+type SavedEntities = Or<(With<Machine>, With<MachinePlaceholder>, With<Storage>)>;
+
+#[allow(clippy::too_many_arguments)]
+pub fn load_game(
+    mut commands: Commands,
+    mut loads: EventReader<LoadGame>,
+    mut tick: ResMut<CurrentTick>,
+    mut last_report: ResMut<LoadReport>,
+    packs: Res<ContentPacks>,
+    asset_root: Res<AssetRoot>,
+    item_types: Res<ItemTypeList>,
+    recipe_list: Res<RecipeList>,
+    machine_list: Res<MachineList>,
+    mut registry: ResMut<IdRegistry>,
+    q: Query<Entity, SavedEntities>,
+) {
+    // only the last load of a frame matters
+    let Some(LoadGame(path)) = loads.read().last() else {
+        return;
+    };
+    let (save, version) = match read_save(path) {
+        Ok(save) => save,
+        Err(e) => {
+            error!("Loading failed: {}", e);
+            return;
+        }
+    };
+    let mut report = LoadReport { migrated_from: (version < SAVE_VERSION).then_some(version), ..default() };
+    report.compare_packs(&save.header.packs, &PackFingerprint::of_packs(&packs, asset_root.path()));
+    for entity in q.iter() {
+        commands.entity(entity).despawn_recursive();
+    }
+    for saved in save.machines.iter() {
+        let transform = TransformBundle::from_transform(Transform::from_translation(Vec3::from_array(saved.position)));
+        let Some(template) = registry.machines.get(&saved.template).and_then(|id| machine_list.0.get(&id)) else {
+            report.placeholders.push(format!("unknown machine `{}` at {:?}", saved.template, saved.position));
+            commands.spawn((MachinePlaceholder(saved.clone()), transform));
+            continue;
+        };
+        let mut bundle = MachineBundle {
+            input: InputInventory(saved.input.restore(&item_types, &mut registry, &mut report)),
+            output: OutputInventory(saved.output.restore(&item_types, &mut registry, &mut report)),
+            recipe: SetRecipe(None),
+            state: saved.state,
+            crafting_timer: saved.progress,
+            craft_inputs: CraftInputs(
+                saved.craft_inputs.iter().filter_map(|s| s.restore(&item_types, &mut registry, &mut report)).collect()
+            ),
+        };
+        if let Some(key) = &saved.recipe {
+            match registry.recipes.get(key).and_then(|id| recipe_list.0.get(&id)) {
+                Some(recipe) if template.can_craft(recipe.id) => bundle.recipe = SetRecipe(Some(recipe.clone())),
+                // the recipe is gone or the machine doesn't craft it anymore, a craft that was started gets its inputs back
+                found => {
+                    let refund = std::mem::take(&mut bundle.craft_inputs.0);
+                    bundle.state = MachineState::Idle;
+                    bundle.crafting_timer = CraftingTimer::default();
+                    let problem = if found.is_some() { "uncraftable" } else { "unknown" };
+                    report.cleared_recipes.push(
+                        format!("{} recipe `{}` of machine `{}` at {:?}", problem, key, saved.template, saved.position)
+                    );
+                    if !refund.is_empty() {
+                        let items: Vec<String> = refund.iter().map(|s| format!("{} of `{}`", s.size, s.item_type.key)).collect();
+                        report.refunds.push(
+                            format!("{} to machine `{}` at {:?}", items.join(", "), saved.template, saved.position)
+                        );
+                    }
+                    for stack in bundle.input.0.add(&refund) {
+                        report.lost.push(format!("{} of `{}` that didn't fit back into the input", stack.size, stack.item_type.key));
+                    }
+                }
+            }
+        }
+        let entity = commands.spawn_empty().id();
+        // reservations aren't saved, a craft in progress takes its output space again
+        if let (MachineState::Crafting | MachineState::Complete, Some(recipe)) = (bundle.state, &bundle.recipe.0) {
+            reserve_output(&mut bundle.output, entity, &recipe.outputs);
+        }
+        commands.entity(entity).insert((Machine(template.clone()), bundle, transform));
+    }
+    for saved in save.storages.iter() {
+        let transform = TransformBundle::from_transform(Transform::from_translation(Vec3::from_array(saved.position)));
+        commands.spawn((saved.restore(&item_types, &mut registry, &mut report), transform));
+    }
+    tick.0 = save.tick;
+    println!("Loaded {} machine(s) and {} storage(s) from {}", save.machines.len(), save.storages.len(), path.display());
+    report.print();
+    *last_report = report;
+}
+
+#[cfg(test)]
+mod tests {
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::testing::*;
+
+    // a registry and item list that know `known`
+    fn content(known: &[&ItemType]) -> (ItemTypeList, IdRegistry) {
+        let mut item_types = ItemTypeList::default();
+        let mut registry = IdRegistry::default();
+        for item_type in known.iter() {
+            assert_eq!(registry.items.intern(&item_type.key), Some(item_type.id));
+            item_types.insert((*item_type).clone());
+        }
+        (item_types, registry)
+    }
+
+    fn saved_machine(template: &str, recipe: Option<&str>) -> SavedMachine {
+        SavedMachine {
+            template: template.to_string(),
+            position: [1.0, 2.0, 0.0],
+            recipe: recipe.map(str::to_string),
+            state: MachineState::Crafting,
+            progress: CraftingTimer { elapsed: 10, duration: 60 },
+            craft_inputs: vec![SavedStack { item: "test:item_0".to_string(), size: 1 }],
+            input: SavedInventory { slots: vec![Some(SavedStack { item: "test:item_0".to_string(), size: 5 }), None] },
+            output: SavedInventory { slots: vec![None] },
+        }
+    }
+
+    #[test]
+    fn saves_read_back_as_written() {
+        let save = SaveFile {
+            header: SaveHeader {
+                version: SAVE_VERSION,
+                packs: vec![PackFingerprint { name: "base".to_string(), version: "0.1.0".to_string(), fingerprint: 7 }],
+            },
+            tick: 1234,
+            machines: vec![saved_machine("base:extruder", Some("base:iron_rod")), saved_machine("base:press", None)],
+            storages: vec![SavedStorage {
+                position: [0.0, 3.0, 0.0],
+                backend: StorageBackend::Hashed,
+                slots: 20,
+                contents: vec![SavedStack { item: "test:item_0".to_string(), size: 100 }],
+            }],
+        };
+        let path = std::env::temp_dir().join(format!("bevy-automation-round-trip-{}.save.ron", std::process::id()));
+        write_save(&path, &save).unwrap();
+        let read = read_save(&path);
+        let _ = fs::remove_file(&path);
+        let (read, version) = read.unwrap();
+        assert_eq!(version, SAVE_VERSION);
+        assert_eq!(ron::to_string(&read).unwrap(), ron::to_string(&save).unwrap());
+    }
+
+    #[test]
+    fn inventories_keep_their_layout() {
+        let plate = item(0, 10);
+        let rod = item(1, 10);
+        let (item_types, mut registry) = content(&[&plate, &rod]);
+        let mut inventory = Inventory::new(4);
+        inventory.auto_compact = false;
+        inventory.slots = vec![None, Some(stack(&rod, 3)), None, Some(stack(&plate, 10))];
+        let mut report = LoadReport::default();
+        let restored = SavedInventory::capture(&inventory).restore(&item_types, &mut registry, &mut report);
+        assert_eq!(restored.slots, inventory.slots);
+        assert!(report.lost.is_empty());
+    }
+
+    fn saved(stacks: &[(&str, ItemCount)]) -> Vec<Option<SavedStack>> {
+        stacks.iter().map(|(item, size)| Some(SavedStack { item: item.to_string(), size: *size })).collect()
+    }
+
+    #[test]
+    fn unknown_items_are_kept_as_placeholders() {
+        let plate = item(0, 10);
+        let (item_types, mut registry) = content(&[&plate]);
+        let saved = SavedInventory { slots: saved(&[("test:item_0", 4), ("test:removed", 30)]) };
+        let mut report = LoadReport::default();
+        let restored = saved.restore(&item_types, &mut registry, &mut report);
+        assert_eq!(restored.slot(0), Some(&stack(&plate, 4)));
+        assert_eq!(restored.slot(1).map(|s| (s.item_type.key.as_str(), s.size)), Some(("test:removed", 30)));
+        assert_eq!(report.placeholder_items, vec!["30 of unknown item `test:removed`".to_string()]);
+        assert!(report.lost.is_empty());
+        // saving again writes the unknown item back as it was
+        assert_eq!(SavedInventory::capture(&restored).slots, saved.slots);
+    }
+
+    #[test]
+    fn unknown_items_in_storages_survive_a_round_trip() {
+        let plate = item(0, 10);
+        for backend in [StorageBackend::Slotted, StorageBackend::Hashed] {
+            let (item_types, mut registry) = content(&[&plate]);
+            let saved = SavedStorage {
+                position: [0.0; 3],
+                backend,
+                slots: 3,
+                contents: saved(&[("test:item_0", 10), ("test:removed", 500)]).into_iter().flatten().collect(),
+            };
+            let mut report = LoadReport::default();
+            let restored = saved.restore(&item_types, &mut registry, &mut report);
+            assert!(report.lost.is_empty(), "{:?}", backend);
+            assert_eq!(SavedStorage::capture(&restored, &Transform::default()).contents, saved.contents, "{:?}", backend);
+        }
+    }
+
+    #[test]
+    fn unknown_items_are_lost_without_an_id_for_a_placeholder() {
+        let (item_types, mut registry) = content(&[]);
+        for i in 0..=u16::MAX {
+            registry.items.intern(&format!("test:filler_{}", i));
+        }
+        let mut report = LoadReport::default();
+        let restored = SavedInventory { slots: saved(&[("test:removed", 3)]) }.restore(&item_types, &mut registry, &mut report);
+        assert!(restored.is_empty());
+        assert_eq!(report.lost, vec!["3 of unknown item `test:removed`".to_string()]);
+    }
+
+    #[test]
+    fn storages_keep_their_backend_and_contents() {
+        let plate = item(0, 10);
+        let (item_types, mut registry) = content(&[&plate]);
+        for backend in [StorageBackend::Slotted, StorageBackend::Hashed] {
+            let mut storage = Storage::new(backend, 3);
+            storage.insert(&[stack(&plate, 25)]);
+            let mut report = LoadReport::default();
+            let saved = SavedStorage::capture(&storage, &Transform::default());
+            let restored = saved.restore(&item_types, &mut registry, &mut report);
+            assert_eq!(restored.backend(), backend);
+            assert_eq!(restored.capacity(), 3);
+            assert_eq!(restored.count(&plate), 25);
+        }
+    }
+
+    // a world with the given content that just loaded `save`
+    fn load(
+        name: &str,
+        save: &SaveFile,
+        item_types: ItemTypeList,
+        registry: IdRegistry,
+        recipes: &[Recipe],
+        machines: &[MachineTemplate],
+    ) -> World {
+        let path = std::env::temp_dir().join(format!("bevy-automation-{}-{}.save.ron", name, std::process::id()));
+        write_save(&path, save).unwrap();
+        let mut world = World::new();
+        world.init_resource::<Events<LoadGame>>();
+        world.init_resource::<CurrentTick>();
+        world.init_resource::<LoadReport>();
+        world.insert_resource(ContentPacks(vec![ContentPack::base()]));
+        world.insert_resource(AssetRoot(std::env::temp_dir()));
+        world.insert_resource(item_types);
+        world.insert_resource(RecipeList(recipes.iter().map(|r| (r.id, r.clone())).collect()));
+        world.insert_resource(MachineList(machines.iter().map(|m| (m.id, m.clone())).collect()));
+        world.insert_resource(registry);
+        world.send_event(LoadGame(path.clone()));
+        world.run_system_once(load_game);
+        let _ = fs::remove_file(&path);
+        world
+    }
+
+    fn save_of(machines: Vec<SavedMachine>) -> SaveFile {
+        SaveFile {
+            header: SaveHeader { version: SAVE_VERSION, packs: Vec::new() },
+            tick: 0,
+            machines,
+            storages: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn unknown_recipes_are_cleared_and_their_craft_refunded() {
+        let plate = item(0, 10);
+        let (item_types, mut registry) = content(&[&plate]);
+        let machine = machine_template(0, &[]);
+        registry.machines.intern(&machine.key);
+        let mut saved = saved_machine(&machine.key, Some("test:removed"));
+        let mut world = load("refund", &save_of(vec![saved.clone()]), item_types, registry.clone(), &[], std::slice::from_ref(&machine));
+        let report = world.resource::<LoadReport>();
+        assert_eq!(report.cleared_recipes.len(), 1);
+        assert_eq!(report.refunds.len(), 1);
+        assert!(report.lost.is_empty());
+        let (recipe, state, input) = world.query::<(&SetRecipe, &MachineState, &InputInventory)>().single(&world);
+        assert!(recipe.0.is_none());
+        assert_eq!(*state, MachineState::Idle);
+        assert_eq!(input.0.count(&plate), 6);
+
+        // nothing to refund, the recipe is still only cleared and nothing is lost
+        saved.craft_inputs.clear();
+        let world = load("cleared", &save_of(vec![saved]), content(&[&plate]).0, registry, &[], &[machine]);
+        let report = world.resource::<LoadReport>();
+        assert_eq!(report.cleared_recipes.len(), 1);
+        assert!(report.refunds.is_empty());
+        assert!(report.lost.is_empty());
+    }
+
+    #[test]
+    fn recipes_the_machine_cant_craft_are_cleared() {
+        let plate = item(0, 10);
+        let (item_types, mut registry) = content(&[&plate]);
+        let press = recipe(0, 60, &[stack(&plate, 1)], &[], &[stack(&plate, 1)]);
+        assert_eq!(registry.recipes.intern(&press.key), Some(press.id));
+        // the machine crafted the recipe when the game was saved, the content changed since
+        let machine = machine_template(0, &[]);
+        registry.machines.intern(&machine.key);
+        let saved = saved_machine(&machine.key, Some(&press.key));
+        let mut world = load("uncraftable", &save_of(vec![saved]), item_types, registry, std::slice::from_ref(&press), &[machine]);
+        let report = world.resource::<LoadReport>();
+        assert_eq!(report.cleared_recipes, vec![
+            format!("uncraftable recipe `{}` of machine `test:machine_0` at [1.0, 2.0, 0.0]", press.key)
+        ]);
+        assert_eq!(report.refunds.len(), 1);
+        let (recipe, state, input, craft_inputs) = world.query::<(&SetRecipe, &MachineState, &InputInventory, &CraftInputs)>().single(&world);
+        assert!(recipe.0.is_none());
+        assert_eq!(*state, MachineState::Idle);
+        assert_eq!(input.0.count(&plate), 6);
+        assert!(craft_inputs.0.is_empty());
+    }
+
+    #[test]
+    fn placeholders_count_towards_the_checksum() {
+        let checksum = |name: &str, save: &SaveFile| {
+            let mut world = load(name, save, ItemTypeList::default(), IdRegistry::default(), &[], &[]);
+            assert_eq!(world.query::<&MachinePlaceholder>().iter(&world).count(), save.machines.len());
+            world.run_system_once(|factory: FactoryState| factory.checksum())
+        };
+        let empty = checksum("no-placeholder", &save_of(Vec::new()));
+        let one = checksum("placeholder", &save_of(vec![saved_machine("test:removed", None)]));
+        let mut other = saved_machine("test:removed", None);
+        other.progress.elapsed += 1;
+        let changed = checksum("changed-placeholder", &save_of(vec![other]));
+        assert_ne!(empty, one);
+        assert_ne!(one, changed);
+    }
+
+    #[test]
+    fn pack_changes_are_reported() {
+        let pack = |name: &str, version: &str, fingerprint: u64| PackFingerprint {
+            name: name.to_string(),
+            version: version.to_string(),
+            fingerprint,
+        };
+        let mut report = LoadReport::default();
+        report.compare_packs(
+            &[pack("base", "1", 1), pack("gears", "1", 2), pack("old", "1", 3), pack("same", "1", 4)],
+            &[pack("base", "1", 9), pack("gears", "2", 2), pack("same", "1", 4), pack("new", "1", 5)],
+        );
+        assert_eq!(report.content_changes, vec![
+            "the content of pack `base` changed".to_string(),
+            "pack `gears` was version 1, now 2".to_string(),
+            "pack `old` is not loaded anymore".to_string(),
+            "pack `new` was added".to_string(),
+        ]);
+    }
+}
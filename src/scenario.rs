@@ -0,0 +1,248 @@
+use std::collections::BTreeMap;
+
+use bevy::prelude::*;
+
+use crate::state::AppState;
+use crate::ids::*;
+use crate::item::*;
+use crate::recipe::*;
+use crate::machine::*;
+use crate::inventory::*;
+use crate::pack::*;
+
+pub const DEFAULT_SCENARIO: &str = "scenarios/default.scenario.ron";
+
+// spawns the machines of the scenario chosen at startup once the game starts
+pub struct ScenarioPlugin {
+    // asset path of the scenario file
+    pub path: String,
+}
+
+impl Plugin for ScenarioPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SelectedScenario(self.path.clone()));
+        app.add_systems(OnEnter(AppState::InGame), spawn_scenario);
+    }
+}
+
+// the world a game starts with, `*.scenario.ron`
+// ids without a namespace get the one of the pack the file is in
+#[derive(serde::Deserialize, Asset, TypePath, Clone, Debug)]
+pub struct Scenario {
+    pub name: String,
+    #[serde(default)]
+    pub machines: Vec<ScenarioMachine>,
+    #[serde(default)]
+    pub storages: Vec<ScenarioStorage>,
+}
+
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct ScenarioMachine {
+    pub template: String,
+    #[serde(default)]
+    pub position: [f32; 3],
+    #[serde(default)]
+    pub recipe: Option<String>,
+    // starting inventory contents by item id
+    #[serde(default)]
+    pub input: BTreeMap<String, ItemCount>,
+    #[serde(default)]
+    pub output: BTreeMap<String, ItemCount>,
+}
+
+// a plain item store, e.g. a warehouse
+#[derive(serde::Deserialize, Clone, Debug)]
+pub struct ScenarioStorage {
+    #[serde(default)]
+    pub position: [f32; 3],
+    #[serde(default)]
+    pub backend: StorageBackend,
+    pub slots: usize,
+    #[serde(default)]
+    pub contents: BTreeMap<String, ItemCount>,
+}
+
+impl Scenario {
+    pub fn qualify(&mut self, namespace: &str) {
+        for machine in self.machines.iter_mut() {
+            machine.template = qualify(&machine.template, namespace);
+            machine.recipe = machine.recipe.as_ref().map(|r| qualify(r, namespace));
+            machine.input = machine.input.iter().map(|(key, amount)| (qualify(key, namespace), *amount)).collect();
+            machine.output = machine.output.iter().map(|(key, amount)| (qualify(key, namespace), *amount)).collect();
+        }
+        for storage in self.storages.iter_mut() {
+            storage.contents = storage.contents.iter().map(|(key, amount)| (qualify(key, namespace), *amount)).collect();
+        }
+    }
+}
+
+#[derive(Resource, Clone, Debug)]
+pub struct SelectedScenario(pub String);
+
+// loaded together with the asset folders
+#[derive(Resource, Clone, Debug)]
+pub struct ScenarioHandle(pub Handle<Scenario>);
+
+// the selected scenario with qualified ids, and the file it came from
+pub fn selected_scenario(
+    handle: &ScenarioHandle,
+    scenarios: &Assets<Scenario>,
+    server: &AssetServer,
+    packs: &ContentPacks,
+) -> Option<(String, Scenario)> {
+    let mut scenario = scenarios.get(&handle.0)?.clone();
+    let file = server.get_path(handle.0.id()).map(|p| p.to_string()).unwrap_or_default();
+    scenario.qualify(packs.get(packs.pack_of(&file)).namespace());
+    Some((file, scenario))
+}
+
+fn stacks_of(amounts: &BTreeMap<String, ItemCount>, item_types: &ItemTypeList, registry: &IdRegistry) -> Vec<ItemStack> {
+    amounts
+        .iter()
+        .filter_map(|(key, amount)| {
+            let item_type = registry.items.get(key).and_then(|id| item_types.get(id))?;
+            Some(ItemStack::new(item_type.clone(), *amount))
+        })
+        .collect()
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_scenario(
+    mut commands: Commands,
+    handle: Res<ScenarioHandle>,
+    scenarios: Res<Assets<Scenario>>,
+    server: Res<AssetServer>,
+    packs: Res<ContentPacks>,
+    item_types: Res<ItemTypeList>,
+    recipe_list: Res<RecipeList>,
+    machine_list: Res<MachineList>,
+    registry: Res<IdRegistry>,
+) {
+    let Some((file, scenario)) = selected_scenario(&handle, &scenarios, &server, &packs) else {
+        error!("The scenario isn't loaded");
+        return;
+    };
+    println!("Starting scenario {} ({})", scenario.name, file);
+    spawn_contents(&mut commands, &file, &scenario, &item_types, &recipe_list, &machine_list, &registry);
+}
+
+// the scenario was validated with the rest of the assets, anything unknown here is skipped
+fn spawn_contents(
+    commands: &mut Commands,
+    file: &str,
+    scenario: &Scenario,
+    item_types: &ItemTypeList,
+    recipe_list: &RecipeList,
+    machine_list: &MachineList,
+    registry: &IdRegistry,
+) {
+    for machine in scenario.machines.iter() {
+        let Some(template) = registry.machines.get(&machine.template).and_then(|id| machine_list.0.get(&id)) else {
+            warn!("Skipping unknown machine `{}` in {}", machine.template, file);
+            continue;
+        };
+        let recipe = machine.recipe
+            .as_ref()
+            .and_then(|key| registry.recipes.get(key))
+            .and_then(|id| recipe_list.0.get(&id))
+            .cloned();
+        let mut bundle = MachineBundle { recipe: SetRecipe(recipe), ..default() };
+        // sprites are added by the presentation layer, if there is one
+        for (inventory, amounts) in [(&mut bundle.input.0, &machine.input), (&mut bundle.output.0, &machine.output)] {
+            for stack in inventory.add(&stacks_of(amounts, item_types, registry)) {
+                warn!("{} of `{}` don't fit into machine `{}` in {}", stack.size, stack.item_type.key, machine.template, file);
+            }
+        }
+        commands.spawn((
+            Machine(template.clone()),
+            bundle,
+            TransformBundle::from_transform(Transform::from_translation(Vec3::from_array(machine.position))),
+        ));
+        println!("Spawned machine {}", template.name);
+    }
+    for (i, scenario_storage) in scenario.storages.iter().enumerate() {
+        let mut storage = Storage::new(scenario_storage.backend, scenario_storage.slots);
+        for stack in storage.insert(&stacks_of(&scenario_storage.contents, item_types, registry)) {
+            warn!("{} of `{}` don't fit into storage {} in {}", stack.size, stack.item_type.key, i, file);
+        }
+        commands.spawn((
+            storage,
+            TransformBundle::from_transform(Transform::from_translation(Vec3::from_array(scenario_storage.position))),
+        ));
+        println!("Spawned {:?} storage with {} slots", scenario_storage.backend, scenario_storage.slots);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use bevy::ecs::system::RunSystemOnce;
+
+    use super::*;
+    use crate::testing::*;
+
+    const SCENARIO: &str = r#"(
+        name: "Test",
+        machines: [
+            (template: "extruder", recipe: Some("iron_rod"), input: {"iron_plate": 10, "base:coal": 2}),
+            (template: "gears:press", position: (1.0, 2.0, 0.0)),
+        ],
+        storages: [(slots: 4, contents: {"iron_plate": 5})],
+    )"#;
+
+    #[test]
+    fn scenarios_parse_with_defaults_and_get_namespaced() {
+        let mut scenario: Scenario = ron::from_str(SCENARIO).unwrap();
+        scenario.qualify(DEFAULT_NAMESPACE);
+        assert_eq!(scenario.name, "Test");
+        let (extruder, press) = (&scenario.machines[0], &scenario.machines[1]);
+        assert_eq!((extruder.template.as_str(), extruder.position), ("base:extruder", [0.0; 3]));
+        assert_eq!(extruder.recipe.as_deref(), Some("base:iron_rod"));
+        assert_eq!(extruder.input.keys().collect::<Vec<_>>(), vec!["base:coal", "base:iron_plate"]);
+        assert!(extruder.output.is_empty());
+        // ids that already have a namespace keep it
+        assert_eq!((press.template.as_str(), press.position, &press.recipe), ("gears:press", [1.0, 2.0, 0.0], &None));
+        let storage = &scenario.storages[0];
+        assert_eq!((storage.backend, storage.slots), (StorageBackend::Slotted, 4));
+        assert_eq!(storage.contents.get("base:iron_plate"), Some(&5));
+    }
+
+    #[test]
+    fn spawning_skips_unknown_machines_and_items() {
+        let plate = ItemType { key: "base:iron_plate".to_string(), ..item(0, 10) };
+        let rod = ItemType { key: "base:iron_rod".to_string(), ..item(1, 10) };
+        let extrude = Recipe { key: "base:iron_rod".to_string(), ..recipe(0, 60, &[stack(&plate, 1)], &[], &[stack(&rod, 1)]) };
+        let extruder = MachineTemplate { key: "base:extruder".to_string(), ..machine_template(0, &[&extrude]) };
+        let mut registry = IdRegistry::default();
+        registry.items.intern(&plate.key);
+        registry.items.intern(&rod.key);
+        registry.recipes.intern(&extrude.key);
+        registry.machines.intern(&extruder.key);
+        let mut item_types = ItemTypeList::default();
+        item_types.insert(plate.clone());
+        item_types.insert(rod.clone());
+        let recipes = RecipeList(HashMap::from([(extrude.id, extrude.clone())]));
+        let machines = MachineList(HashMap::from([(extruder.id, extruder.clone())]));
+
+        let mut scenario: Scenario = ron::from_str(SCENARIO).unwrap();
+        scenario.qualify(DEFAULT_NAMESPACE);
+        let mut world = World::new();
+        world.run_system_once(move |mut commands: Commands| {
+            spawn_contents(&mut commands, "test.scenario.ron", &scenario, &item_types, &recipes, &machines, &registry);
+        });
+
+        // the press and the coal aren't defined
+        let (machine, recipe, input, output) = world
+            .query::<(&Machine, &SetRecipe, &InputInventory, &OutputInventory)>()
+            .single(&world);
+        assert_eq!(machine.0.key, extruder.key);
+        assert_eq!(recipe.0.as_ref().map(|r| r.id), Some(extrude.id));
+        assert_eq!(input.0.stacks().cloned().collect::<Vec<_>>(), vec![stack(&plate, 10)]);
+        assert!(output.0.is_empty());
+        let (storage, transform) = world.query::<(&Storage, &Transform)>().single(&world);
+        assert_eq!((storage.backend(), storage.capacity()), (StorageBackend::Slotted, 4));
+        assert_eq!(storage.count(&plate), 5);
+        assert_eq!(transform.translation, Vec3::ZERO);
+    }
+}
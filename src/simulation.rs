@@ -0,0 +1,335 @@
+use std::collections::BTreeMap;
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::time::Duration;
+
+use bevy::prelude::*;
+use bevy::ecs::schedule::ScheduleLabel;
+use bevy::ecs::system::SystemParam;
+
+use crate::state::AppState;
+use crate::asset::AssetPlugin;
+use crate::inventory::InventoryPlugin;
+use crate::machine::*;
+use crate::inventory::*;
+use crate::ids::*;
+use crate::item::ItemCount;
+use crate::save::MachinePlaceholder;
+
+pub const TICKS_PER_SECOND: u32 = 60;
+pub const TICK_LENGTH: Duration = Duration::from_nanos(1_000_000_000 / TICKS_PER_SECOND as u64);
+
+// one step of the simulation, everything that changes the factory runs here in a fixed order
+#[derive(ScheduleLabel, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct SimTick;
+
+// number of ticks simulated so far
+#[derive(Resource, Default, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CurrentTick(pub u64);
+
+impl CurrentTick {
+    // simulated time, what reservation timeouts are measured in
+    pub fn elapsed(&self) -> Duration {
+        let ticks_per_second = u64::from(TICKS_PER_SECOND);
+        Duration::from_secs(self.0 / ticks_per_second) + TICK_LENGTH * (self.0 % ticks_per_second) as u32
+    }
+}
+
+// everything the factory needs to run, without rendering or input
+// works with either DefaultPlugins or MinimalPlugins plus an asset and states plugin
+pub struct SimulationPlugin;
+
+impl Plugin for SimulationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            AssetPlugin,
+            InventoryPlugin,
+            MachinePlugin,
+        ));
+        app.init_state::<AppState>();
+        app.init_schedule(SimTick);
+        app.init_resource::<CurrentTick>()
+            .init_resource::<SimClock>()
+            .init_resource::<ProductionStats>();
+        app.add_systems(
+            Update,
+            run_sim_ticks.run_if(in_state(AppState::InGame))
+        );
+        app.add_systems(
+            Update,
+            record_production.run_if(in_state(AppState::InGame))
+        );
+    }
+}
+
+// decides how many ticks run each frame, the simulation itself only sees whole ticks
+#[derive(Resource, Clone, Debug)]
+pub struct SimClock {
+    paused: bool,
+    speed: u32,
+    // ticks asked for with `step`, run even while paused
+    pending_steps: u32,
+    // game time that hasn't been simulated yet
+    accumulator: Duration,
+    // frames never run more ticks than this, the rest is dropped so a slow frame can't snowball
+    pub max_ticks_per_frame: u32,
+}
+
+impl Default for SimClock {
+    fn default() -> Self {
+        SimClock {
+            paused: false,
+            speed: 1,
+            pending_steps: 0,
+            accumulator: Duration::ZERO,
+            max_ticks_per_frame: 128,
+        }
+    }
+}
+
+impl SimClock {
+    pub const MIN_SPEED: u32 = 1;
+    pub const MAX_SPEED: u32 = 64;
+
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+        self.accumulator = Duration::ZERO;
+    }
+
+    pub fn toggle_pause(&mut self) {
+        self.set_paused(!self.paused);
+    }
+
+    // run a number of ticks on the next frame, meant for stepping through a paused game
+    pub fn step(&mut self, ticks: u32) {
+        self.pending_steps = self.pending_steps.saturating_add(ticks);
+    }
+
+    pub fn speed(&self) -> u32 {
+        self.speed
+    }
+
+    // ticks per tick length of game time, clamped to 1x-64x
+    pub fn set_speed(&mut self, speed: u32) {
+        self.speed = speed.clamp(Self::MIN_SPEED, Self::MAX_SPEED);
+    }
+
+    pub fn faster(&mut self) {
+        self.set_speed(self.speed.saturating_mul(2));
+    }
+
+    pub fn slower(&mut self) {
+        self.set_speed(self.speed / 2);
+    }
+
+    // how many ticks to run for a frame that took `delta`
+    pub fn advance(&mut self, delta: Duration) -> u32 {
+        let steps = std::mem::take(&mut self.pending_steps);
+        if self.paused {
+            return steps.min(self.max_ticks_per_frame);
+        }
+        self.accumulator += delta * self.speed;
+        let due = self.accumulator.as_nanos() / TICK_LENGTH.as_nanos();
+        let ticks = u32::try_from(due).unwrap_or(u32::MAX).saturating_add(steps);
+        if ticks > self.max_ticks_per_frame {
+            self.accumulator = Duration::ZERO;
+            return self.max_ticks_per_frame;
+        }
+        self.accumulator -= TICK_LENGTH * u32::try_from(due).unwrap_or(0);
+        ticks
+    }
+}
+
+// the tick schedule is run by hand, so the simulation only depends on how many ticks ran and not on frame times
+pub fn run_sim_ticks(world: &mut World) {
+    let delta = world.resource::<Time>().delta();
+    let ticks = world.resource_mut::<SimClock>().advance(delta);
+    for _ in 0..ticks {
+        run_sim_tick(world);
+    }
+}
+
+pub fn run_sim_tick(world: &mut World) {
+    world.run_schedule(SimTick);
+    world.resource_mut::<CurrentTick>().0 += 1;
+}
+
+// the components that make up a machine's state
+pub type FactoryMachine = (
+    &'static Machine,
+    &'static SetRecipe,
+    &'static MachineState,
+    &'static CraftingTimer,
+    &'static CraftInputs,
+    &'static InputInventory,
+    &'static OutputInventory,
+    &'static Transform,
+);
+
+// everything that decides how the factory continues
+#[derive(SystemParam)]
+pub struct FactoryState<'w, 's> {
+    pub tick: Res<'w, CurrentTick>,
+    pub machines: Query<'w, 's, FactoryMachine>,
+    pub storages: Query<'w, 's, (&'static Storage, &'static Transform)>,
+    pub placeholders: Query<'w, 's, &'static MachinePlaceholder>,
+}
+
+impl FactoryState<'_, '_> {
+    // two runs that end up in the same state have the same checksum
+    // machines are hashed on their own and then in sorted order, so entity ids don't matter
+    pub fn checksum(&self) -> u64 {
+        let mut machine_hashes: Vec<u64> = self.machines
+            .iter()
+            .map(|(machine, recipe, state, timer, craft_inputs, input, output, transform)| {
+                let mut hasher = DefaultHasher::new();
+                machine.0.key.hash(&mut hasher);
+                recipe.0.as_ref().map(|r| &r.key).hash(&mut hasher);
+                state.hash(&mut hasher);
+                timer.hash(&mut hasher);
+                for stack in craft_inputs.0.iter() {
+                    (&stack.item_type.key, stack.size).hash(&mut hasher);
+                }
+                hash_inventory(&input.0, &mut hasher);
+                hash_inventory(&output.0, &mut hasher);
+                transform.translation.to_array().map(f32::to_bits).hash(&mut hasher);
+                hasher.finish()
+            })
+            // placeholders are hashed as they would be saved, they hold on to all of it
+            .chain(self.placeholders.iter().map(|placeholder| {
+                let mut hasher = DefaultHasher::new();
+                ron::to_string(&placeholder.0).unwrap_or_default().hash(&mut hasher);
+                hasher.finish()
+            }))
+            .collect();
+        machine_hashes.sort();
+        let mut storage_hashes: Vec<u64> = self.storages
+            .iter()
+            .map(|(storage, transform)| {
+                let mut hasher = DefaultHasher::new();
+                storage.backend().hash(&mut hasher);
+                storage.capacity().hash(&mut hasher);
+                let mut stacks: Vec<(String, ItemCount)> = storage.stacks().into_iter().map(|s| (s.item_type.key, s.size)).collect();
+                stacks.sort();
+                stacks.hash(&mut hasher);
+                transform.translation.to_array().map(f32::to_bits).hash(&mut hasher);
+                hasher.finish()
+            })
+            .collect();
+        storage_hashes.sort();
+        let mut hasher = DefaultHasher::new();
+        self.tick.0.hash(&mut hasher);
+        machine_hashes.hash(&mut hasher);
+        storage_hashes.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+fn hash_inventory(inventory: &Inventory, hasher: &mut DefaultHasher) {
+    for slot in inventory.slots.iter() {
+        slot.as_ref().map(|s| (&s.item_type.key, s.size)).hash(hasher);
+    }
+}
+
+// totals since the game started, keyed by recipe and item ids
+#[derive(Resource, Default, Debug)]
+pub struct ProductionStats {
+    pub crafts: BTreeMap<String, u64>,
+    pub produced: BTreeMap<String, u64>,
+    pub consumed: BTreeMap<String, u64>,
+}
+
+impl ProductionStats {
+    pub fn print(&self) {
+        for (title, totals) in [("Crafts", &self.crafts), ("Produced", &self.produced), ("Consumed", &self.consumed)] {
+            println!("{}:", title);
+            if totals.is_empty() {
+                println!("  nothing");
+            }
+            for (key, amount) in totals.iter() {
+                println!("  {}: {}", key, amount);
+            }
+        }
+    }
+}
+
+fn record_production(
+    mut started: EventReader<CraftStarted>,
+    mut completed: EventReader<CraftCompleted>,
+    registry: Res<IdRegistry>,
+    mut stats: ResMut<ProductionStats>,
+) {
+    for event in started.read() {
+        for stack in event.inputs.iter() {
+            *stats.consumed.entry(stack.item_type.key.clone()).or_default() += u64::from(stack.size);
+        }
+    }
+    for event in completed.read() {
+        let recipe_key = registry.recipes.key(event.recipe_id).unwrap_or("<unknown recipe>");
+        *stats.crafts.entry(recipe_key.to_string()).or_default() += 1;
+        for stack in event.outputs.iter() {
+            *stats.produced.entry(stack.item_type.key.clone()).or_default() += u64::from(stack.size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn one_tick_per_tick_length() {
+        let mut clock = SimClock::default();
+        assert_eq!(clock.advance(TICK_LENGTH), 1);
+        assert_eq!(clock.advance(TICK_LENGTH * 3), 3);
+        // partial ticks carry over to the next frame
+        assert_eq!(clock.advance(TICK_LENGTH / 2), 0);
+        assert_eq!(clock.advance(TICK_LENGTH / 2), 1);
+    }
+
+    #[test]
+    fn speed_multiplies_ticks_and_is_clamped() {
+        let mut clock = SimClock::default();
+        clock.set_speed(8);
+        assert_eq!(clock.advance(TICK_LENGTH), 8);
+        clock.set_speed(1000);
+        assert_eq!(clock.speed(), SimClock::MAX_SPEED);
+        clock.set_speed(0);
+        assert_eq!(clock.speed(), SimClock::MIN_SPEED);
+        clock.slower();
+        assert_eq!(clock.speed(), SimClock::MIN_SPEED);
+        clock.faster();
+        assert_eq!(clock.speed(), 2);
+    }
+
+    #[test]
+    fn paused_clock_only_runs_steps() {
+        let mut clock = SimClock::default();
+        clock.set_paused(true);
+        assert_eq!(clock.advance(TICK_LENGTH * 10), 0);
+        clock.step(3);
+        assert_eq!(clock.advance(TICK_LENGTH * 10), 3);
+        assert_eq!(clock.advance(TICK_LENGTH), 0);
+        // time that passed while paused isn't made up for
+        clock.toggle_pause();
+        assert_eq!(clock.advance(TICK_LENGTH), 1);
+    }
+
+    #[test]
+    fn slow_frames_are_capped() {
+        let mut clock = SimClock { max_ticks_per_frame: 10, ..default() };
+        assert_eq!(clock.advance(TICK_LENGTH * 25), 10);
+        // the rest was dropped instead of piling up
+        assert_eq!(clock.advance(TICK_LENGTH), 1);
+    }
+
+    #[test]
+    fn elapsed_time_follows_ticks() {
+        assert_eq!(CurrentTick(0).elapsed(), Duration::ZERO);
+        assert_eq!(CurrentTick(u64::from(TICKS_PER_SECOND) * 2).elapsed(), Duration::from_secs(2));
+        assert_eq!(CurrentTick(1).elapsed(), TICK_LENGTH);
+    }
+}
@@ -5,6 +5,9 @@ pub enum AppState {
     #[default]
     LoadingAssetFolders,
     LoadingAssets,
+    ValidatingAssets,
+    // a file failed to load or the definitions have problems, see ValidationReport
+    AssetError,
     InGame,
     //Finished,
 }
@@ -0,0 +1,48 @@
+// content for unit tests, built in code instead of loaded from assets
+use crate::item::*;
+use crate::recipe::*;
+use crate::machine::*;
+
+pub fn item(id: u16, max_stack: ItemCount) -> ItemType {
+    ItemType {
+        name: format!("Item {}", id),
+        key: format!("test:item_{}", id),
+        id,
+        max_stack,
+        tags: Vec::new(),
+        category: "misc".to_string(),
+    }
+}
+
+pub fn stack(item_type: &ItemType, size: ItemCount) -> ItemStack {
+    ItemStack::new(item_type.clone(), size)
+}
+
+pub fn tagged_item(id: u16, max_stack: ItemCount, tags: &[&str]) -> ItemType {
+    ItemType { tags: tags.iter().map(|t| t.to_string()).collect(), ..item(id, max_stack) }
+}
+
+pub fn recipe(id: u16, ticks: u32, inputs: &[ItemStack], tag_inputs: &[(&str, ItemCount)], outputs: &[ItemStack]) -> Recipe {
+    Recipe {
+        name: format!("Recipe {}", id),
+        key: format!("test:recipe_{}", id),
+        id,
+        ticks,
+        inputs: inputs.to_vec(),
+        tag_inputs: tag_inputs.iter().map(|(tag, amount)| TagIngredient { tag: tag.to_string(), amount: *amount }).collect(),
+        outputs: outputs.to_vec(),
+    }
+}
+
+pub fn machine_template(id: u16, recipes: &[&Recipe]) -> MachineTemplate {
+    MachineTemplate {
+        name: format!("Machine {}", id),
+        sprite_name: String::new(),
+        key: format!("test:machine_{}", id),
+        id,
+        crafting_speed: 1.0,
+        crafting_categories: Vec::new(),
+        valid_recipes: recipes.iter().map(|r| r.key.clone()).collect(),
+        valid_recipe_ids: recipes.iter().map(|r| r.id).collect(),
+    }
+}
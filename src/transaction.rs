@@ -0,0 +1,225 @@
+use std::fmt::Display;
+
+use bevy::prelude::Entity;
+
+use crate::item::*;
+use crate::inventory::*;
+
+#[derive(Debug)]
+pub enum TransactionError {
+    MissingItems { inventory: usize },
+    NoSpace { inventory: usize, leftovers: Vec<ItemStack> },
+    UnknownInventory { inventory: usize },
+}
+
+impl Display for TransactionError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransactionError::MissingItems { inventory } =>
+                write!(f, "inventory {} does not contain the requested items", inventory),
+            TransactionError::NoSpace { inventory, leftovers } =>
+                write!(f, "inventory {} has no space for {} stack(s)", inventory, leftovers.len()),
+            TransactionError::UnknownInventory { inventory } =>
+                write!(f, "inventory {} is not part of this transaction", inventory),
+        }
+    }
+}
+
+enum Operation {
+    Add(Option<Entity>, Vec<ItemStack>),
+    Remove(Vec<ItemStack>),
+}
+
+// stages adds and removes across several containers and applies them all or none of them
+// nothing is touched until `commit`, `rollback` or dropping the transaction throws the staged operations away
+pub struct Transaction<'a> {
+    containers: Vec<&'a mut dyn ItemContainer>,
+    staged: Vec<(usize, Operation)>,
+}
+
+impl<'a> Transaction<'a> {
+    pub fn begin() -> Self {
+        Transaction {
+            containers: Vec::new(),
+            staged: Vec::new(),
+        }
+    }
+
+    // add a container to the transaction, returns the key used to stage operations on it
+    pub fn enlist(&mut self, container: &'a mut dyn ItemContainer) -> usize {
+        self.containers.push(container);
+        self.containers.len() - 1
+    }
+
+    pub fn add(&mut self, inventory: usize, stacks: &[ItemStack]) -> &mut Self {
+        self.add_as(inventory, None, stacks)
+    }
+
+    // add on behalf of `owner`, which can use the slots it reserved
+    pub fn add_as(&mut self, inventory: usize, owner: Option<Entity>, stacks: &[ItemStack]) -> &mut Self {
+        self.staged.push((inventory, Operation::Add(owner, stacks.to_vec())));
+        self
+    }
+
+    pub fn remove(&mut self, inventory: usize, stacks: &[ItemStack]) -> &mut Self {
+        self.staged.push((inventory, Operation::Remove(stacks.to_vec())));
+        self
+    }
+
+    // apply every staged operation to copies of the containers, and only swap them in if all succeeded
+    pub fn commit(self) -> Result<(), TransactionError> {
+        let mut working: Vec<Box<dyn ItemContainer>> = self.containers.iter().map(|c| c.boxed_copy()).collect();
+        for (inventory, operation) in self.staged.iter() {
+            let Some(target) = working.get_mut(*inventory) else {
+                return Err(TransactionError::UnknownInventory { inventory: *inventory });
+            };
+            match operation {
+                Operation::Add(owner, stacks) => {
+                    let leftovers = target.insert_as(*owner, stacks);
+                    if !leftovers.is_empty() {
+                        return Err(TransactionError::NoSpace { inventory: *inventory, leftovers });
+                    }
+                }
+                Operation::Remove(stacks) => {
+                    if !target.extract(stacks) {
+                        return Err(TransactionError::MissingItems { inventory: *inventory });
+                    }
+                }
+            }
+        }
+        for (container, result) in self.containers.into_iter().zip(working) {
+            container.replace_with(result);
+        }
+        Ok(())
+    }
+
+    // give up on the staged operations, the containers keep what they held when they were enlisted
+    pub fn rollback(self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::*;
+
+    #[test]
+    fn commit_applies_every_operation() {
+        let plate = item(1, 10);
+        let mut from = Inventory::new(2);
+        let mut to = Inventory::new(2);
+        from.add(&[stack(&plate, 15)]);
+        let mut transaction = Transaction::begin();
+        let source = transaction.enlist(&mut from);
+        let target = transaction.enlist(&mut to);
+        transaction.remove(source, &[stack(&plate, 12)]).add(target, &[stack(&plate, 12)]);
+        assert!(transaction.commit().is_ok());
+        assert_eq!(from.count(&plate), 3);
+        assert_eq!(to.count(&plate), 12);
+    }
+
+    #[test]
+    fn failed_add_leaves_every_container_untouched() {
+        let plate = item(1, 10);
+        let rod = item(2, 10);
+        let mut from = Inventory::new(2);
+        let mut to = Inventory::new(1);
+        from.add(&[stack(&plate, 15)]);
+        to.add(&[stack(&plate, 5)]);
+        let (from_before, to_before) = (from.to_string(), to.to_string());
+        let mut transaction = Transaction::begin();
+        let source = transaction.enlist(&mut from);
+        let target = transaction.enlist(&mut to);
+        // the plates would partly fit, the rods not at all
+        transaction
+            .remove(source, &[stack(&plate, 8)])
+            .add(target, &[stack(&plate, 8)])
+            .add(target, &[stack(&rod, 1)]);
+        assert!(matches!(transaction.commit(), Err(TransactionError::NoSpace { inventory: 1, .. })));
+        assert_eq!(from.to_string(), from_before);
+        assert_eq!(to.to_string(), to_before);
+        // no events for items that were never really added
+        assert_eq!(to.changes.clone().drain(), vec![(plate.id, 5)]);
+    }
+
+    #[test]
+    fn failed_remove_leaves_every_container_untouched() {
+        let plate = item(1, 10);
+        let mut from = Inventory::new(2);
+        let mut to = Inventory::new(2);
+        from.add(&[stack(&plate, 5)]);
+        let mut transaction = Transaction::begin();
+        let source = transaction.enlist(&mut from);
+        let target = transaction.enlist(&mut to);
+        transaction.add(target, &[stack(&plate, 6)]).remove(source, &[stack(&plate, 6)]);
+        assert!(matches!(transaction.commit(), Err(TransactionError::MissingItems { inventory: 0 })));
+        assert_eq!(from.count(&plate), 5);
+        assert!(to.is_empty());
+    }
+
+    #[test]
+    fn unknown_inventory_is_an_error() {
+        let plate = item(1, 10);
+        let mut inventory = Inventory::new(1);
+        let mut transaction = Transaction::begin();
+        let key = transaction.enlist(&mut inventory);
+        transaction.add(key, &[stack(&plate, 1)]).add(key + 1, &[stack(&plate, 1)]);
+        assert!(matches!(transaction.commit(), Err(TransactionError::UnknownInventory { inventory: 1 })));
+        assert!(inventory.is_empty());
+    }
+
+    #[test]
+    fn rollback_changes_nothing() {
+        let plate = item(1, 10);
+        let mut from = Inventory::new(1);
+        let mut to = Inventory::new(1);
+        from.add(&[stack(&plate, 10)]);
+        let mut transaction = Transaction::begin();
+        let source = transaction.enlist(&mut from);
+        let target = transaction.enlist(&mut to);
+        transaction.remove(source, &[stack(&plate, 10)]).add(target, &[stack(&plate, 10)]);
+        transaction.rollback();
+        assert_eq!(from.count(&plate), 10);
+        assert!(to.is_empty());
+        assert_eq!(from.changes.clone().drain(), vec![(plate.id, 10)]);
+    }
+
+    #[test]
+    fn dropped_transaction_changes_nothing() {
+        let plate = item(1, 10);
+        let mut inventory = Inventory::new(1);
+        {
+            let mut transaction = Transaction::begin();
+            let key = transaction.enlist(&mut inventory);
+            transaction.add(key, &[stack(&plate, 1)]);
+        }
+        assert!(inventory.is_empty());
+    }
+
+    #[test]
+    fn adds_are_all_or_nothing() {
+        let plate = item(1, 10);
+        let mut inventory = Inventory::new(2);
+        for (amount, added) in [(25, false), (20, true)] {
+            let mut transaction = Transaction::begin();
+            let key = transaction.enlist(&mut inventory);
+            transaction.add(key, &[stack(&plate, amount)]);
+            assert_eq!(transaction.commit().is_ok(), added);
+        }
+        assert_eq!(inventory.count(&plate), 20);
+    }
+
+    #[test]
+    fn works_on_every_container_type() {
+        let plate = item(1, 10);
+        let mut slotted = Storage::new(StorageBackend::Slotted, 1);
+        let mut hashed = Storage::new(StorageBackend::Hashed, 1);
+        hashed.insert(&[stack(&plate, 10)]);
+        let mut transaction = Transaction::begin();
+        let source = transaction.enlist(&mut hashed);
+        let target = transaction.enlist(&mut slotted);
+        transaction.remove(source, &[stack(&plate, 10)]).add(target, &[stack(&plate, 10)]);
+        assert!(transaction.commit().is_ok());
+        assert!(hashed.is_empty());
+        assert_eq!(slotted.count(&plate), 10);
+    }
+}
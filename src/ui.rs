@@ -1 +1,176 @@
+use std::path::Path;
+
 use bevy::prelude::*;
+use bevy_pancam::{PanCam, PanCamPlugin};
+use bevy_inspector_egui::quick::WorldInspectorPlugin;
+
+use crate::asset::*;
+use crate::inventory::*;
+use crate::machine::*;
+use crate::simulation::*;
+use crate::save::*;
+use crate::state::AppState;
+
+// camera, sprites and debug tools, everything the headless mode leaves out
+pub struct PresentationPlugin;
+
+impl Plugin for PresentationPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugins((
+            WorldInspectorPlugin::new(),
+            PanCamPlugin,
+        ));
+        app.add_systems(Startup, spawn_camera);
+        app.add_systems(Update, (update_machine_sprites, clock_controls, save_controls, recipe_controls, sort_controls, slot_controls));
+        app.add_systems(Update, loading_text.run_if(in_state(AppState::LoadingAssetFolders)));
+    }
+}
+
+fn spawn_camera(
+    mut commands: Commands
+) {
+    commands.spawn(Camera2dBundle::default())
+        .insert(PanCam::default());
+}
+
+// show how far loading got whenever it moves on
+fn loading_text(progress: Res<LoadingProgress>) {
+    if progress.is_changed() {
+        println!(
+            "Loading {:.0}% ({} of {} files, {} failed)",
+            progress.fraction() * 100.0, progress.loaded, progress.total, progress.failed
+        );
+    }
+}
+
+type MachineSprite<'a> = (Entity, &'a Machine, Option<&'a Handle<Image>>);
+
+// give new machines a sprite, and swap it when a reload changed the machine's sprite
+fn update_machine_sprites(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    q: Query<MachineSprite, Changed<Machine>>,
+) {
+    for (entity, machine, texture) in q.iter() {
+        let sprite_name = &machine.0.sprite_name;
+        match texture {
+            Some(texture) if texture.path().is_some_and(|p| p.path() == Path::new(sprite_name)) => (),
+            Some(_) => {
+                commands.entity(entity).insert(asset_server.load::<Image>(sprite_name.clone()));
+            }
+            None => {
+                commands.entity(entity).insert((
+                    Sprite::default(),
+                    asset_server.load::<Image>(sprite_name.clone()),
+                    VisibilityBundle::default(),
+                ));
+            }
+        }
+    }
+}
+
+// space pauses, period steps one tick while paused, plus and minus change the speed
+fn clock_controls(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut clock: ResMut<SimClock>,
+) {
+    if keys.just_pressed(KeyCode::Space) {
+        clock.toggle_pause();
+        println!("{}", if clock.is_paused() { "Paused" } else { "Resumed" });
+    }
+    if keys.just_pressed(KeyCode::Period) && clock.is_paused() {
+        clock.step(1);
+    }
+    if keys.any_just_pressed([KeyCode::Equal, KeyCode::NumpadAdd]) {
+        clock.faster();
+        println!("Speed {}x", clock.speed());
+    }
+    if keys.any_just_pressed([KeyCode::Minus, KeyCode::NumpadSubtract]) {
+        clock.slower();
+        println!("Speed {}x", clock.speed());
+    }
+}
+
+// F5 quicksaves, F9 loads the quicksave
+fn save_controls(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut saves: EventWriter<SaveGame>,
+    mut loads: EventWriter<LoadGame>,
+) {
+    if keys.just_pressed(KeyCode::F5) {
+        saves.send(SaveGame(QUICKSAVE_PATH.into()));
+    }
+    if keys.just_pressed(KeyCode::F9) {
+        loads.send(LoadGame(QUICKSAVE_PATH.into()));
+    }
+}
+
+// R switches every machine to the next recipe it can craft, and back to none after the last one
+fn recipe_controls(
+    keys: Res<ButtonInput<KeyCode>>,
+    q: Query<(Entity, &Machine, &SetRecipe)>,
+    mut requests: EventWriter<SetRecipeRequest>,
+) {
+    if !keys.just_pressed(KeyCode::KeyR) {
+        return;
+    }
+    for (entity, machine, recipe) in q.iter() {
+        let recipes = &machine.0.valid_recipe_ids;
+        let recipe_id = match recipe.0.as_ref().and_then(|r| recipes.iter().position(|id| *id == r.id)) {
+            Some(i) => recipes.get(i + 1).copied(),
+            None => recipes.first().copied(),
+        };
+        requests.send(SetRecipeRequest { entity, recipe_id });
+    }
+}
+
+// S sorts every storage and lists what it holds, each press uses the next sort mode
+fn sort_controls(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut mode: Local<SortMode>,
+    mut q: Query<(Entity, &mut Storage)>,
+) {
+    if !keys.just_pressed(KeyCode::KeyS) {
+        return;
+    }
+    for (entity, mut storage) in q.iter_mut() {
+        let stacks: Vec<String> = storage.sort(*mode).iter().map(|s| s.to_string()).collect();
+        println!("Storage {:?} sorted by {:?}: [{}]", entity, *mode, stacks.join(", "));
+    }
+    *mode = mode.next();
+}
+
+// on the first slot of every slotted storage: X splits it in half, C swaps it with the last slot,
+// L locks it to the item it holds or unlocks it
+fn slot_controls(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut q: Query<(Entity, &mut Storage)>,
+) {
+    if !keys.any_just_pressed([KeyCode::KeyX, KeyCode::KeyC, KeyCode::KeyL]) {
+        return;
+    }
+    for (entity, mut storage) in q.iter_mut() {
+        let Storage::Slotted(inventory) = storage.as_mut() else {
+            continue;
+        };
+        if keys.just_pressed(KeyCode::KeyX) {
+            let half = inventory.slot(0).map_or(0, |s| s.size / 2);
+            match inventory.split_slot(0, half) {
+                Some(target) => println!("Storage {:?} split {} items into slot {}", entity, half, target),
+                None => println!("Storage {:?} can't split its first slot", entity),
+            }
+        }
+        if keys.just_pressed(KeyCode::KeyC) && !inventory.swap_slots(0, inventory.size() - 1) {
+            println!("Storage {:?} can't swap its first and last slot", entity);
+        }
+        if keys.just_pressed(KeyCode::KeyL) {
+            let filter = match (&inventory.slot_filters[0], inventory.slot(0)) {
+                (ItemFilter::Any, Some(stack)) => ItemFilter::Items([stack.item_type.id].into()),
+                _ => ItemFilter::Any,
+            };
+            println!("Storage {:?} {} its first slot", entity, if matches!(filter, ItemFilter::Any) { "unlocked" } else { "locked" });
+            inventory.set_slot_filter(0, filter);
+        }
+        println!("Storage {:?}: {}", entity, inventory);
+    }
+}
@@ -0,0 +1,342 @@
+use std::collections::HashSet;
+use std::fmt::Display;
+use std::path::Path;
+
+use bevy::prelude::*;
+
+use crate::state::AppState;
+use crate::asset::AssetRoot;
+use crate::definitions::*;
+use crate::item::*;
+use crate::recipe::*;
+use crate::machine::*;
+use crate::pack::*;
+use crate::scenario::*;
+
+// a single problem found in a definition file
+#[derive(Clone, Debug)]
+pub struct AssetProblem {
+    pub file: String,
+    pub message: String,
+}
+
+impl Display for AssetProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.file, self.message)
+    }
+}
+
+// everything wrong with the loaded definitions, empty if they can be used
+// warnings are likely mistakes that don't stop the definitions from being used
+#[derive(Resource, Default, Clone, Debug)]
+pub struct ValidationReport {
+    pub problems: Vec<AssetProblem>,
+    pub warnings: Vec<AssetProblem>,
+}
+
+impl ValidationReport {
+    pub fn push(&mut self, file: &str, message: String) {
+        self.problems.push(AssetProblem { file: file.to_string(), message });
+    }
+
+    pub fn warn(&mut self, file: &str, message: String) {
+        self.warnings.push(AssetProblem { file: file.to_string(), message });
+    }
+
+    pub fn append(&mut self, other: &mut ValidationReport) {
+        self.problems.append(&mut other.problems);
+        self.warnings.append(&mut other.warnings);
+    }
+
+    pub fn print_warnings(&self) {
+        for warning in self.warnings.iter() {
+            warn!("{}", warning);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+pub fn asset_file<A: Asset>(server: &AssetServer, id: AssetId<A>) -> String {
+    server.get_path(id).map(|p| p.to_string()).unwrap_or_else(|| "<unknown file>".to_string())
+}
+
+pub fn validate_item_types(items: &[Definition<ItemType>], report: &mut ValidationReport) {
+    for definition in items.iter() {
+        let item_type = &definition.value;
+        if item_type.max_stack == 0 {
+            report.push(&definition.file, format!("item `{}` has a max stack of 0", item_type.key));
+        }
+    }
+}
+
+pub fn validate_recipes(
+    recipes: &[Definition<RecipeTemplate>],
+    item_types: &ItemTypeList,
+    report: &mut ValidationReport,
+) {
+    let item_keys: HashSet<&str> = item_types.iter().map(|t| t.key.as_str()).collect();
+    for definition in recipes.iter() {
+        let (file, recipe) = (&definition.file, &definition.value);
+        let key = &recipe.key;
+        if recipe.ticks == 0 {
+            report.push(file, format!("recipe `{}` takes 0 ticks, it must take at least one", key));
+        }
+        for (direction, amounts) in [("input", &recipe.inputs), ("output", &recipe.outputs)] {
+            for (item_key, amount) in amounts.iter() {
+                if !item_keys.contains(item_key.as_str()) {
+                    report.push(file, format!("recipe `{}` has unknown {} item `{}`", key, direction, item_key));
+                }
+                if *amount == 0 {
+                    report.push(file, format!("recipe `{}` has 0 of {} item `{}`", key, direction, item_key));
+                }
+            }
+        }
+        for (tag, amount) in recipe.tag_inputs.iter() {
+            if item_types.with_tag(tag).next().is_none() {
+                report.push(file, format!("recipe `{}` has an input tag `{}` that no item has", key, tag));
+            }
+            if *amount == 0 {
+                report.push(file, format!("recipe `{}` has 0 of input tag `{}`", key, tag));
+            }
+        }
+    }
+}
+
+pub fn validate_machines(
+    machines: &[Definition<MachineTemplate>],
+    recipes: &[Definition<RecipeTemplate>],
+    asset_root: &Path,
+    report: &mut ValidationReport,
+) {
+    let recipe_keys: HashSet<&str> = recipes.iter().map(|r| r.value.key.as_str()).collect();
+    for definition in machines.iter() {
+        let (file, machine) = (&definition.file, &definition.value);
+        let key = &machine.key;
+        if !machine.crafting_speed.is_finite() || machine.crafting_speed <= 0.0 {
+            report.push(file, format!("machine `{}` has a crafting speed of {}, it must be positive", key, machine.crafting_speed));
+        }
+        for recipe_key in machine.valid_recipes.iter() {
+            if !recipe_keys.contains(recipe_key.as_str()) {
+                report.push(file, format!("machine `{}` lists unknown recipe `{}`", key, recipe_key));
+            }
+        }
+        // usually a typo in the category, which would silently leave the machine without those recipes
+        for category in machine.crafting_categories.iter() {
+            if !recipes.iter().any(|r| r.value.category.as_ref() == Some(category)) {
+                report.warn(file, format!("machine `{}` crafts category `{}` that no recipe has", key, category));
+            }
+        }
+        if !recipes.iter().any(|r| machine.accepts_recipe(&r.value)) {
+            report.warn(file, format!("machine `{}` can't craft any recipe", key));
+        }
+        if !asset_root.join(&machine.sprite_name).is_file() {
+            report.push(file, format!("machine `{}` uses missing sprite `{}`", key, machine.sprite_name));
+        }
+    }
+}
+
+// check the merged definitions against the lists built from them
+pub fn validate_definitions(definitions: &Definitions, item_types: &ItemTypeList, asset_root: &Path) -> ValidationReport {
+    let mut report = definitions.problems.clone();
+    validate_item_types(&definitions.items, &mut report);
+    validate_recipes(&definitions.recipes, item_types, &mut report);
+    validate_machines(&definitions.machines, &definitions.recipes, asset_root, &mut report);
+    report
+}
+
+// everything a scenario refers to has to exist, and machines can only be given recipes they can craft
+pub fn validate_scenario(
+    file: &str,
+    scenario: &Scenario,
+    definitions: &Definitions,
+    item_types: &ItemTypeList,
+    report: &mut ValidationReport,
+) {
+    let item_keys: HashSet<&str> = item_types.iter().map(|t| t.key.as_str()).collect();
+    for machine in scenario.machines.iter() {
+        let template = definitions.machines.iter().map(|m| &m.value).find(|m| m.key == machine.template);
+        if template.is_none() {
+            report.push(file, format!("scenario places unknown machine `{}`", machine.template));
+        }
+        if let Some(recipe_key) = &machine.recipe {
+            match definitions.recipes.iter().map(|r| &r.value).find(|r| r.key == *recipe_key) {
+                None => report.push(file, format!("scenario gives machine `{}` unknown recipe `{}`", machine.template, recipe_key)),
+                Some(recipe) if template.is_some_and(|t| !t.accepts_recipe(recipe)) => {
+                    report.push(file, format!("machine `{}` can't craft recipe `{}`", machine.template, recipe_key));
+                }
+                Some(_) => (),
+            }
+        }
+        for (item_key, amount) in machine.input.iter().chain(machine.output.iter()) {
+            if !item_keys.contains(item_key.as_str()) {
+                report.push(file, format!("scenario gives machine `{}` unknown item `{}`", machine.template, item_key));
+            }
+            if *amount == 0 {
+                report.push(file, format!("scenario gives machine `{}` 0 of item `{}`", machine.template, item_key));
+            }
+        }
+    }
+    for (i, storage) in scenario.storages.iter().enumerate() {
+        if storage.slots == 0 {
+            report.push(file, format!("scenario storage {} has no slots", i));
+        }
+        for (item_key, amount) in storage.contents.iter() {
+            if !item_keys.contains(item_key.as_str()) {
+                report.push(file, format!("scenario gives storage {} unknown item `{}`", i, item_key));
+            }
+            if *amount == 0 {
+                report.push(file, format!("scenario gives storage {} 0 of item `{}`", i, item_key));
+            }
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+pub fn validate_assets(
+    mut report: ResMut<ValidationReport>,
+    mut app_next_state: ResMut<NextState<AppState>>,
+    definitions: Res<Definitions>,
+    item_types: Res<ItemTypeList>,
+    scenario: Option<Res<ScenarioHandle>>,
+    scenarios: Res<Assets<Scenario>>,
+    server: Res<AssetServer>,
+    packs: Res<ContentPacks>,
+    asset_root: Res<AssetRoot>,
+) {
+    let mut found = validate_definitions(&definitions, &item_types, asset_root.path());
+    if let Some((file, scenario)) = scenario.and_then(|s| selected_scenario(&s, &scenarios, &server, &packs)) {
+        validate_scenario(&file, &scenario, &definitions, &item_types, &mut found);
+    }
+    // added to what building the definition lists already reported
+    report.append(&mut found);
+    report.print_warnings();
+
+    if report.is_empty() {
+        println!("Assets validated!");
+        app_next_state.set(AppState::InGame);
+    } else {
+        error!("Found {} problem(s) in the asset files:", report.problems.len());
+        for problem in report.problems.iter() {
+            error!("  {}", problem);
+        }
+        app_next_state.set(AppState::AssetError);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::inventory::StorageBackend;
+    use crate::testing::*;
+
+    fn defined<T>(value: T) -> Definition<T> {
+        Definition { value, file: "test.ron".to_string(), pack: 0, overridden: Vec::new(), patches: Vec::new() }
+    }
+
+    fn recipe_template(key: &str, category: Option<&str>) -> RecipeTemplate {
+        RecipeTemplate {
+            name: key.to_string(),
+            key: key.to_string(),
+            id: 0,
+            category: category.map(str::to_string),
+            ticks: 1,
+            inputs: HashMap::new(),
+            tag_inputs: HashMap::new(),
+            outputs: HashMap::new(),
+        }
+    }
+
+    // messages of the problems and the warnings
+    fn machine_report(machine: MachineTemplate, recipes: &[RecipeTemplate]) -> (Vec<String>, Vec<String>) {
+        let recipes: Vec<_> = recipes.iter().cloned().map(defined).collect();
+        let mut report = ValidationReport::default();
+        validate_machines(&[defined(machine)], &recipes, Path::new(env!("CARGO_MANIFEST_DIR")), &mut report);
+        // the test machines have no sprite
+        let problems = report.problems.into_iter().map(|p| p.message).filter(|m| !m.contains("sprite")).collect();
+        (problems, report.warnings.into_iter().map(|p| p.message).collect())
+    }
+
+    #[test]
+    fn machines_crafting_by_category_are_valid() {
+        let machine = MachineTemplate { crafting_categories: vec!["smelting".to_string()], ..machine_template(1, &[]) };
+        let (problems, warnings) = machine_report(machine, &[recipe_template("test:smelt", Some("smelting"))]);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert!(warnings.is_empty(), "{:?}", warnings);
+    }
+
+    #[test]
+    fn categories_no_recipe_has_are_warnings() {
+        let machine = MachineTemplate { crafting_categories: vec!["smeltnig".to_string()], ..machine_template(1, &[]) };
+        let (problems, warnings) = machine_report(machine, &[recipe_template("test:smelt", Some("smelting"))]);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(warnings, vec![
+            "machine `test:machine_1` crafts category `smeltnig` that no recipe has".to_string(),
+            "machine `test:machine_1` can't craft any recipe".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn machines_without_recipes_are_warnings() {
+        let (problems, warnings) = machine_report(machine_template(1, &[]), &[recipe_template("test:smelt", None)]);
+        assert!(problems.is_empty(), "{:?}", problems);
+        assert_eq!(warnings, vec!["machine `test:machine_1` can't craft any recipe".to_string()]);
+    }
+
+    // messages of the problems in a scenario placing one machine with a recipe and items
+    fn scenario_problems(template: &str, recipe: &str, item_key: &str) -> Vec<String> {
+        let definitions = Definitions {
+            recipes: vec![defined(recipe_template("test:smelt", Some("smelting")))],
+            machines: vec![defined(MachineTemplate { crafting_categories: vec!["smelting".to_string()], ..machine_template(1, &[]) })],
+            ..default()
+        };
+        let mut item_types = ItemTypeList::default();
+        item_types.insert(item(0, 10));
+        let scenario = Scenario {
+            name: "Test".to_string(),
+            machines: vec![ScenarioMachine {
+                template: template.to_string(),
+                position: [0.0; 3],
+                recipe: Some(recipe.to_string()),
+                input: [(item_key.to_string(), 1)].into(),
+                output: Default::default(),
+            }],
+            storages: vec![ScenarioStorage { position: [0.0; 3], backend: StorageBackend::Slotted, slots: 4, contents: [(item_key.to_string(), 5)].into() }],
+        };
+        let mut report = ValidationReport::default();
+        validate_scenario("test.scenario.ron", &scenario, &definitions, &item_types, &mut report);
+        report.problems.into_iter().map(|p| p.message).collect()
+    }
+
+    #[test]
+    fn known_scenario_contents_are_valid() {
+        let problems = scenario_problems("test:machine_1", "test:smelt", "test:item_0");
+        assert!(problems.is_empty(), "{:?}", problems);
+    }
+
+    #[test]
+    fn scenarios_with_unknown_templates_are_rejected() {
+        assert_eq!(scenario_problems("test:furnace", "test:smelt", "test:item_0"), vec![
+            "scenario places unknown machine `test:furnace`".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn scenarios_with_unknown_recipes_are_rejected() {
+        assert_eq!(scenario_problems("test:machine_1", "test:melt", "test:item_0"), vec![
+            "scenario gives machine `test:machine_1` unknown recipe `test:melt`".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn scenarios_with_unknown_items_are_rejected() {
+        assert_eq!(scenario_problems("test:machine_1", "test:smelt", "test:ore"), vec![
+            "scenario gives machine `test:machine_1` unknown item `test:ore`".to_string(),
+            "scenario gives storage 0 unknown item `test:ore`".to_string(),
+        ]);
+    }
+}